@@ -10,6 +10,8 @@ use std::fmt::Debug;
 pub struct KubefiConfig {
     pub crd_schema_path: PathBuf,
     pub replace_existing_crd: bool,
+    pub resync_interval_secs: u64,
+    pub metrics_port: u16,
 }
 
 pub fn read_kubefi_config() -> Result<KubefiConfig, Error> {
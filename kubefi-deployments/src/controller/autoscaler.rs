@@ -0,0 +1,62 @@
+use std::rc::Rc;
+
+use anyhow::Result;
+use k8s_openapi::api::autoscaling::v2beta2::HorizontalPodAutoscaler;
+use kube::api::{DeleteParams, PostParams};
+use kube::Client;
+
+use crate::controller::{from_yaml, get_api, get_or_create, json_subset};
+use crate::crd::NiFiDeploymentSpec;
+use crate::template::Template;
+
+use super::either::Either::{Left, Right};
+
+/// HorizontalPodAutoscaler of the NiFiDeployment, which exists only when autoscaling is set
+pub struct AutoscalerController {
+    pub client: Rc<Client>,
+    pub template: Rc<Template>,
+}
+
+impl AutoscalerController {
+    pub async fn handle_autoscaler(
+        &self,
+        name: &str,
+        ns: &str,
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<bool> {
+        let hpa =
+            get_or_create::<HorizontalPodAutoscaler, _>(&self.client, name, name, ns, |name| {
+                self.template.nifi_hpa(name, ns, spec)
+            })
+            .await?;
+        let current = match hpa {
+            Left(Some(current)) => current,
+            Right(Some(_)) => return Ok(true),
+            _ => return Ok(false),
+        };
+
+        let api = get_api::<HorizontalPodAutoscaler>(&self.client, ns);
+        match self.template.nifi_hpa(name, ns, spec)? {
+            Some(yaml) => {
+                let expected = from_yaml::<HorizontalPodAutoscaler>(&yaml)?;
+                let spec_json = |hpa: &HorizontalPodAutoscaler| {
+                    serde_json::to_value(&hpa.spec).unwrap_or_default()
+                };
+                if json_subset(&spec_json(&expected), &spec_json(&current)) {
+                    return Ok(false);
+                }
+                debug!("Updating HorizontalPodAutoscaler: {}", &name);
+                let updated = HorizontalPodAutoscaler {
+                    spec: expected.spec,
+                    ..current
+                };
+                api.replace(name, &PostParams::default(), &updated).await?;
+            }
+            None => {
+                debug!("Deleting HorizontalPodAutoscaler: {}", &name);
+                api.delete(name, &DeleteParams::default()).await?;
+            }
+        }
+        Ok(true)
+    }
+}
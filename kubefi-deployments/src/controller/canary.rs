@@ -0,0 +1,670 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Error, Result};
+use k8s_openapi::api::apps::v1::{ControllerRevision, StatefulSet};
+use k8s_openapi::api::core::v1::{ConfigMap, PodTemplateSpec};
+use kube::api::{Meta, ObjectMeta, PatchParams, PatchStrategy, PostParams};
+use kube::Client;
+
+use crate::controller::statefulset::{pod_template_changed, set_partition, NIFI_CONTAINER_NAME};
+use crate::controller::upgrade::{node_timeout, partition, NiFiUpgrade, NodeState};
+use crate::controller::{from_yaml, get_api, hash, read_name, StatusReport};
+use crate::crd::{Canary, NiFiDeployment};
+use crate::template::Template;
+
+pub const CANARY_CONDITION: &str = "Canary";
+/// Set to "true" to roll out a healthy canary to all NiFi nodes
+pub const PROMOTE_CANARY_ANNOTATION: &str = "kubefi.io/promote-canary";
+
+const CONFIG_HASH_ANNOTATION: &str = "kubefi.io/config-hash";
+const CANARY_REVISION_ANNOTATION: &str = "kubefi.io/canary-revision";
+const CANARY_STATE_ANNOTATION: &str = "kubefi.io/canary-state";
+const ROLLED_BACK: &str = "rolled-back";
+
+/// Applies NiFi configuration and Pod changes to canary nodes first.
+///
+/// Changed configuration is written to the canary ConfigMap, which only the Pods at or above
+/// the partition ordinal use. Once all canary nodes are connected, the canary is promoted by
+/// the approval annotation: its configuration is copied to the ConfigMap of all nodes and the
+/// StatefulSet is rolled out without partition. A canary node which does not connect in time
+/// rolls the canary Pods back to the current revision of the StatefulSet. The canary ConfigMap
+/// remembers the rolled back change, so it is not tried again until the spec is changed.
+pub struct NiFiCanary<'a> {
+    pub client: &'a Client,
+    pub template: &'a Template,
+    pub name: &'a str,
+    pub ns: &'a str,
+}
+
+impl<'a> NiFiCanary<'a> {
+    /// Returns the NiFi StatefulSet to apply instead of the expected one
+    pub async fn expected_set(
+        &self,
+        d: &NiFiDeployment,
+        current: &StatefulSet,
+        expected: StatefulSet,
+        canary: &Canary,
+        report: &StatusReport,
+    ) -> Result<StatefulSet> {
+        let yaml = self
+            .template
+            .nifi_configmap(self.name, self.ns, &d.spec)?
+            .ok_or_else(|| Error::msg("NiFi ConfigMap template is empty"))?;
+        let config = from_yaml::<ConfigMap>(&yaml)?.data.unwrap_or_default();
+        let (stable_name, canary_name) = (self.stable_name(), self.canary_name());
+        let api = get_api::<ConfigMap>(self.client, self.ns);
+        let stable = api.get(&stable_name).await?;
+        let canary_cm = match api.get(&canary_name).await {
+            Ok(cm) => Some(cm),
+            Err(kube::Error::Api(e)) if e.code == 404 => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        match canary_step(
+            current,
+            expected,
+            &stable,
+            canary_cm.as_ref(),
+            &config,
+            canary,
+        )? {
+            CanaryStep::Unchanged(set) => {
+                if let Some(cm) = canary_cm {
+                    if rolled_out(current) && !uses_config(current, &canary_name) {
+                        debug!("Deleting ConfigMap: {}", &canary_name);
+                        api.delete(&Meta::name(&cm), &Default::default()).await?;
+                    }
+                }
+                Ok(set)
+            }
+            CanaryStep::Apply(set) => {
+                self.replace_data(stable, config).await?;
+                Ok(set)
+            }
+            CanaryStep::Start {
+                set,
+                revision,
+                first,
+                last,
+            } => {
+                self.write_canary(canary_cm, config, &revision).await?;
+                let msg = format!(
+                    "Canary of nodes {}-{} to {}-{} is started",
+                    self.name, first, self.name, last
+                );
+                report.set(CANARY_CONDITION, true, "Progressing", &msg);
+                Ok(set)
+            }
+            CanaryStep::RolledBack(set) => {
+                let msg = "Canary is rolled back, change the spec to try again";
+                report.set(CANARY_CONDITION, false, "RolledBack", msg);
+                Ok(set)
+            }
+            CanaryStep::Running {
+                set,
+                promoted,
+                first,
+                last,
+            } => {
+                let upgrade = NiFiUpgrade {
+                    client: self.client,
+                    template: self.template,
+                    name: self.name,
+                    ns: self.ns,
+                };
+                let mut nodes = Vec::new();
+                for ordinal in first..=last {
+                    let pod_name = format!("{}-{}", self.name, ordinal);
+                    let state = upgrade.node_state(&pod_name, current, &d.spec).await?;
+                    nodes.push((pod_name, state));
+                }
+                let timeout = node_timeout(&d.spec);
+                let started = partition(current) == first;
+                match verdict(&nodes, timeout, started, promotion_approved(d)) {
+                    Verdict::RollBack(pod_name) => {
+                        let msg = format!(
+                            "Canary node {} did not connect to the cluster within {} seconds",
+                            pod_name, timeout
+                        );
+                        self.roll_back(current, last + 1, &msg, report).await
+                    }
+                    Verdict::Waiting => {
+                        let msg = "Waiting for canary nodes to connect to the cluster";
+                        report.set(CANARY_CONDITION, true, "Progressing", msg);
+                        Ok(set)
+                    }
+                    Verdict::Healthy => {
+                        let msg = format!(
+                            "Canary nodes are connected, annotate with {}: \"true\" to roll out \
+                             to all nodes",
+                            PROMOTE_CANARY_ANNOTATION
+                        );
+                        report.set(CANARY_CONDITION, true, "Healthy", &msg);
+                        Ok(set)
+                    }
+                    Verdict::Promote => {
+                        self.replace_data(stable, config).await?;
+                        self.remove_approval(d).await?;
+                        let msg = "Canary is promoted, rolling out to all nodes";
+                        report.set(CANARY_CONDITION, false, "Promoted", msg);
+                        Ok(*promoted)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Restores the Pod template of the current StatefulSet revision
+    async fn roll_back(
+        &self,
+        current: &StatefulSet,
+        replicas: i32,
+        msg: &str,
+        report: &StatusReport,
+    ) -> Result<StatefulSet> {
+        let revision_name = current
+            .status
+            .as_ref()
+            .and_then(|s| s.current_revision.clone())
+            .ok_or_else(|| Error::msg("StatefulSet has no current revision"))?;
+        let revision = get_api::<ControllerRevision>(self.client, self.ns)
+            .get(&revision_name)
+            .await?;
+        let mut template = revision
+            .data
+            .map(|d| d.0["spec"]["template"].clone())
+            .unwrap_or_default();
+        if let Some(t) = template.as_object_mut() {
+            t.remove("$patch");
+        }
+        let template: PodTemplateSpec = serde_json::from_value(template)?;
+
+        let api = get_api::<ConfigMap>(self.client, self.ns);
+        let patch =
+            json!({ "metadata": { "annotations": { CANARY_STATE_ANNOTATION: ROLLED_BACK }}});
+        api.patch(
+            &self.canary_name(),
+            &PatchParams::default(),
+            serde_json::to_vec(&patch)?,
+        )
+        .await?;
+        report.set(CANARY_CONDITION, false, "RolledBack", msg);
+
+        let mut set = current.clone();
+        if let Some(spec) = set.spec.as_mut() {
+            spec.template = template;
+            spec.replicas = Some(replicas);
+        }
+        set_partition(&mut set, 0);
+        Ok(set)
+    }
+
+    async fn write_canary(
+        &self,
+        current: Option<ConfigMap>,
+        config: BTreeMap<String, String>,
+        revision: &str,
+    ) -> Result<()> {
+        let api = get_api::<ConfigMap>(self.client, self.ns);
+        let mut annotations = BTreeMap::new();
+        annotations.insert(CANARY_REVISION_ANNOTATION.to_string(), revision.to_string());
+        debug!("Writing canary ConfigMap: {}", self.canary_name());
+        match current {
+            Some(cm) => {
+                let updated = ConfigMap {
+                    data: Some(config),
+                    metadata: ObjectMeta {
+                        annotations: Some(annotations),
+                        ..cm.metadata
+                    },
+                    ..cm
+                };
+                api.replace(&self.canary_name(), &PostParams::default(), &updated)
+                    .await?;
+            }
+            None => {
+                let stable = api.get(&self.stable_name()).await?;
+                let cm = ConfigMap {
+                    data: Some(config),
+                    metadata: ObjectMeta {
+                        name: Some(self.canary_name()),
+                        labels: stable.metadata.labels,
+                        annotations: Some(annotations),
+                        ..ObjectMeta::default()
+                    },
+                    ..ConfigMap::default()
+                };
+                api.create(&PostParams::default(), &cm).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn replace_data(
+        &self,
+        stable: ConfigMap,
+        config: BTreeMap<String, String>,
+    ) -> Result<()> {
+        if stable.data.as_ref() == Some(&config) {
+            return Ok(());
+        }
+        let updated = ConfigMap {
+            data: Some(config),
+            ..stable
+        };
+        get_api::<ConfigMap>(self.client, self.ns)
+            .replace(&self.stable_name(), &PostParams::default(), &updated)
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_approval(&self, d: &NiFiDeployment) -> Result<()> {
+        let patch = json!({ "metadata": { "annotations": { PROMOTE_CANARY_ANNOTATION: null }}});
+        let params = PatchParams {
+            patch_strategy: PatchStrategy::Merge,
+            ..PatchParams::default()
+        };
+        get_api::<NiFiDeployment>(self.client, self.ns)
+            .patch(&read_name(d)?, &params, serde_json::to_vec(&patch)?)
+            .await?;
+        Ok(())
+    }
+
+    fn stable_name(&self) -> String {
+        format!("{}-config", self.name)
+    }
+
+    fn canary_name(&self) -> String {
+        format!("{}-config-canary", self.name)
+    }
+}
+
+/// Step of the canary rollout, decided by the rendered configuration and the StatefulSets
+#[derive(Debug, PartialEq)]
+enum CanaryStep {
+    /// All nodes run the expected configuration and Pod template
+    Unchanged(StatefulSet),
+    /// No NiFi Pod runs to try the change on, so it is applied to all nodes
+    Apply(StatefulSet),
+    /// The change is new, canary nodes from `first` to `last` are started with it
+    Start {
+        set: StatefulSet,
+        revision: String,
+        first: i32,
+        last: i32,
+    },
+    /// The change has been rolled back, so it is not tried again until the spec changes
+    RolledBack(StatefulSet),
+    /// Canary nodes run the change, their state decides whether the promoted set is applied
+    Running {
+        set: StatefulSet,
+        promoted: Box<StatefulSet>,
+        first: i32,
+        last: i32,
+    },
+}
+
+/// Outcome of a running canary, by the state of its nodes
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    /// The node did not connect in time
+    RollBack(String),
+    Waiting,
+    /// All canary nodes are connected, the promotion is not approved yet
+    Healthy,
+    Promote,
+}
+
+fn canary_step(
+    current: &StatefulSet,
+    expected: StatefulSet,
+    stable: &ConfigMap,
+    canary_cm: Option<&ConfigMap>,
+    config: &BTreeMap<String, String>,
+    canary: &Canary,
+) -> Result<CanaryStep> {
+    let config_hash = hash(&serde_json::to_string(config)?);
+    let stable_name = Meta::name(stable);
+    let canary_name = format!("{}-canary", stable_name);
+    let hashed = config_hash_of(current).is_some();
+    let stable_set = with_config(
+        expected.clone(),
+        &stable_name,
+        if hashed { Some(&config_hash) } else { None },
+        0,
+    );
+    let mut running = current.clone();
+    rename_config(&mut running, &canary_name, &stable_name);
+    if hashed {
+        set_config_hash(&mut running, &config_hash);
+    }
+    let unchanged = stable.data.as_ref() == Some(config)
+        && !pod_template_changed(&running, &stable_set, NIFI_CONTAINER_NAME);
+    if unchanged {
+        return Ok(CanaryStep::Unchanged(stable_set));
+    }
+
+    let promoted = with_config(expected.clone(), &stable_name, Some(&config_hash), 0);
+    let replicas = expected.spec.as_ref().and_then(|s| s.replicas).unwrap_or(0);
+    if replicas == 0 {
+        // nothing runs to try the change on
+        return Ok(CanaryStep::Apply(promoted));
+    }
+    let (first, last) = ((canary.partition as i32).min(replicas - 1), replicas - 1);
+    let set = with_config(expected, &canary_name, Some(&config_hash), first);
+    let revision = hash(&serde_json::to_string(
+        &set.spec.as_ref().map(|s| &s.template),
+    )?);
+    let annotation = |key: &str| {
+        canary_cm
+            .and_then(|cm| cm.metadata.annotations.as_ref())
+            .and_then(|a| a.get(key).cloned())
+    };
+    if annotation(CANARY_REVISION_ANNOTATION).as_ref() != Some(&revision) {
+        return Ok(CanaryStep::Start {
+            set,
+            revision,
+            first,
+            last,
+        });
+    }
+    if annotation(CANARY_STATE_ANNOTATION).as_deref() == Some(ROLLED_BACK) {
+        let mut kept = current.clone();
+        if let Some(spec) = kept.spec.as_mut() {
+            spec.replicas = Some(replicas);
+        }
+        return Ok(CanaryStep::RolledBack(kept));
+    }
+    Ok(CanaryStep::Running {
+        set,
+        promoted: Box::new(promoted),
+        first,
+        last,
+    })
+}
+
+/// Canary nodes are rolled back once one of them does not connect within the timeout,
+/// and promoted once all are connected, the partition is applied and promotion is approved
+fn verdict(nodes: &[(String, NodeState)], timeout: i64, started: bool, approved: bool) -> Verdict {
+    let mut connected = started;
+    for (pod_name, state) in nodes {
+        match state {
+            NodeState::Connected => (),
+            NodeState::Starting(age) if *age > timeout => {
+                return Verdict::RollBack(pod_name.clone());
+            }
+            _ => connected = false,
+        }
+    }
+    match (connected, approved) {
+        (false, _) => Verdict::Waiting,
+        (true, false) => Verdict::Healthy,
+        (true, true) => Verdict::Promote,
+    }
+}
+
+fn promotion_approved(d: &NiFiDeployment) -> bool {
+    d.metadata
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(PROMOTE_CANARY_ANNOTATION))
+        .map(|v| v == "true")
+        .unwrap_or(false)
+}
+
+/// The set with Pods using the given ConfigMap and restarted on its changes
+fn with_config(
+    mut set: StatefulSet,
+    cm_name: &str,
+    config_hash: Option<&str>,
+    partition: i32,
+) -> StatefulSet {
+    let stable_name = set
+        .metadata
+        .name
+        .clone()
+        .map(|n| format!("{}-config", n))
+        .unwrap_or_default();
+    rename_config(&mut set, &stable_name, cm_name);
+    if let Some(config_hash) = config_hash {
+        set_config_hash(&mut set, config_hash);
+    }
+    set_partition(&mut set, partition);
+    set
+}
+
+fn rename_config(set: &mut StatefulSet, from: &str, to: &str) {
+    let volumes = set
+        .spec
+        .as_mut()
+        .and_then(|s| s.template.spec.as_mut())
+        .and_then(|s| s.volumes.as_mut());
+    for volume in volumes.into_iter().flatten() {
+        if let Some(cm) = volume.config_map.as_mut() {
+            if cm.name.as_deref() == Some(from) {
+                cm.name = Some(to.to_string());
+            }
+        }
+    }
+}
+
+fn set_config_hash(set: &mut StatefulSet, config_hash: &str) {
+    if let Some(spec) = set.spec.as_mut() {
+        spec.template
+            .metadata
+            .get_or_insert_with(Default::default)
+            .annotations
+            .get_or_insert_with(Default::default)
+            .insert(CONFIG_HASH_ANNOTATION.to_string(), config_hash.to_string());
+    }
+}
+
+fn config_hash_of(set: &StatefulSet) -> Option<String> {
+    set.spec
+        .as_ref()
+        .and_then(|s| s.template.metadata.as_ref())
+        .and_then(|m| m.annotations.as_ref())
+        .and_then(|a| a.get(CONFIG_HASH_ANNOTATION).cloned())
+}
+
+fn uses_config(set: &StatefulSet, cm_name: &str) -> bool {
+    set.spec
+        .as_ref()
+        .and_then(|s| s.template.spec.as_ref())
+        .and_then(|s| s.volumes.as_ref())
+        .map(|v| {
+            v.iter()
+                .filter_map(|v| v.config_map.as_ref())
+                .any(|cm| cm.name.as_deref() == Some(cm_name))
+        })
+        .unwrap_or(false)
+}
+
+fn rolled_out(set: &StatefulSet) -> bool {
+    set.status
+        .as_ref()
+        .map(|s| s.current_revision == s.update_revision)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn set() -> StatefulSet {
+        serde_json::from_value(json!({
+            "metadata": { "name": "test" },
+            "spec": {
+                "replicas": 3,
+                "selector": {},
+                "serviceName": "test-headless",
+                "template": { "spec": {
+                    "containers": [{ "name": "server", "image": "apache/nifi:1.12.1" }],
+                    "volumes": [
+                        { "name": "nifi-properties", "configMap": { "name": "test-config" }},
+                        { "name": "logback-xml", "configMap": { "name": "custom-logback" }}
+                    ]
+                }}
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn canary_pods_use_canary_config() {
+        let canary = with_config(set(), "test-config-canary", Some("abc"), 2);
+        assert!(uses_config(&canary, "test-config-canary"));
+        assert!(!uses_config(&canary, "test-config"));
+        assert!(uses_config(&canary, "custom-logback"));
+        assert_eq!(config_hash_of(&canary), Some("abc".to_string()));
+        assert_eq!(partition(&canary), 2);
+
+        let stable = with_config(set(), "test-config", None, 0);
+        assert!(uses_config(&stable, "test-config"));
+        assert_eq!(config_hash_of(&stable), None);
+    }
+
+    fn image(set: &StatefulSet, image: &str) -> StatefulSet {
+        let mut set = set.clone();
+        if let Some(pod) = set.spec.as_mut().and_then(|s| s.template.spec.as_mut()) {
+            pod.containers[0].image = Some(image.to_string());
+        }
+        set
+    }
+
+    fn config_map(name: &str, annotations: Value) -> ConfigMap {
+        serde_json::from_value(json!({
+            "metadata": { "name": name, "annotations": annotations },
+            "data": { "nifi.properties": "nifi.web.http.port=8080" }
+        }))
+        .unwrap()
+    }
+
+    fn config() -> BTreeMap<String, String> {
+        config_map("test-config", json!({})).data.unwrap()
+    }
+
+    fn step(
+        current: &StatefulSet,
+        expected: StatefulSet,
+        canary_cm: Option<&ConfigMap>,
+    ) -> CanaryStep {
+        let stable = config_map("test-config", json!({}));
+        let canary = Canary { partition: 2 };
+        canary_step(current, expected, &stable, canary_cm, &config(), &canary).unwrap()
+    }
+
+    #[test]
+    fn unchanged_set_keeps_stable_config() {
+        match step(&set(), set(), None) {
+            CanaryStep::Unchanged(set) => assert!(uses_config(&set, "test-config")),
+            other => panic!("unexpected step {:?}", other),
+        }
+    }
+
+    #[test]
+    fn start_canary_on_changed_image() {
+        let expected = image(&set(), "apache/nifi:1.13.0");
+        let (canary, revision) = match step(&set(), expected, None) {
+            CanaryStep::Start {
+                set,
+                revision,
+                first,
+                last,
+            } => {
+                assert_eq!((first, last), (2, 2));
+                (set, revision)
+            }
+            other => panic!("unexpected step {:?}", other),
+        };
+        assert!(uses_config(&canary, "test-config-canary"));
+        assert_eq!(partition(&canary), 2);
+
+        // the same change with a canary ConfigMap of another revision starts again
+        let outdated = config_map(
+            "test-config-canary",
+            json!({ CANARY_REVISION_ANNOTATION: "other" }),
+        );
+        let expected = image(&set(), "apache/nifi:1.13.0");
+        match step(&set(), expected, Some(&outdated)) {
+            CanaryStep::Start { revision: r, .. } => assert_eq!(r, revision),
+            other => panic!("unexpected step {:?}", other),
+        }
+    }
+
+    #[test]
+    fn keep_rolled_back_canary() {
+        let expected = image(&set(), "apache/nifi:1.13.0");
+        let revision = match step(&set(), expected.clone(), None) {
+            CanaryStep::Start { revision, .. } => revision,
+            other => panic!("unexpected step {:?}", other),
+        };
+        let rolled_back = config_map(
+            "test-config-canary",
+            json!({ CANARY_REVISION_ANNOTATION: revision, CANARY_STATE_ANNOTATION: ROLLED_BACK }),
+        );
+        match step(&set(), expected.clone(), Some(&rolled_back)) {
+            CanaryStep::RolledBack(kept) => assert_eq!(kept, set()),
+            other => panic!("unexpected step {:?}", other),
+        }
+
+        let running = config_map(
+            "test-config-canary",
+            json!({ CANARY_REVISION_ANNOTATION: revision }),
+        );
+        match step(&set(), expected, Some(&running)) {
+            CanaryStep::Running {
+                set,
+                promoted,
+                first,
+                last,
+            } => {
+                assert_eq!((first, last), (2, 2));
+                assert!(uses_config(&set, "test-config-canary"));
+                assert!(uses_config(&promoted, "test-config"));
+                assert_eq!(partition(&promoted), 0);
+            }
+            other => panic!("unexpected step {:?}", other),
+        }
+    }
+
+    #[test]
+    fn promote_connected_canary_once_approved() {
+        let nodes = |state: NodeState| vec![("test-2".to_string(), state)];
+        let connected = nodes(NodeState::Connected);
+        assert_eq!(verdict(&connected, 600, true, true), Verdict::Promote);
+        assert_eq!(verdict(&connected, 600, true, false), Verdict::Healthy);
+        assert_eq!(verdict(&connected, 600, false, true), Verdict::Waiting);
+        assert_eq!(
+            verdict(&nodes(NodeState::Starting(60)), 600, true, true),
+            Verdict::Waiting
+        );
+        assert_eq!(
+            verdict(&nodes(NodeState::Starting(601)), 600, true, true),
+            Verdict::RollBack("test-2".to_string())
+        );
+    }
+
+    #[test]
+    fn apply_change_without_pods() {
+        let mut expected = image(&set(), "apache/nifi:1.13.0");
+        if let Some(spec) = expected.spec.as_mut() {
+            spec.replicas = Some(0);
+        }
+        match step(&set(), expected, None) {
+            CanaryStep::Apply(set) => {
+                assert!(uses_config(&set, "test-config"));
+                assert!(config_hash_of(&set).is_some());
+            }
+            other => panic!("unexpected step {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stable_hash() {
+        assert_eq!(hash(""), "cbf29ce484222325");
+        assert_ne!(hash("nifi.properties"), hash("nifi.properties "));
+    }
+}
@@ -0,0 +1,189 @@
+use std::rc::Rc;
+
+use anyhow::{Error, Result};
+use k8s_openapi::api::core::v1::Secret;
+use kube::api::{ListParams, Meta, PostParams};
+use kube::Client;
+use kube_derive::CustomResource;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::controller::tls::{common_names_fit, instance_selector, node_hosts, TlsState};
+use crate::controller::{
+    delete_if_exists, from_yaml, get_api, get_or_create, hash, json_subset, StatusReport,
+};
+use crate::crd::{IssuerRef, NiFiDeploymentSpec, TlsMode};
+use crate::template::Template;
+
+use super::either::Either::{Left, Right};
+
+/// Certificate of cert-manager, with the fields Kubefi sets
+#[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug)]
+#[kube(
+    group = "cert-manager.io",
+    version = "v1",
+    namespaced,
+    status = "CertificateStatus"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct CertificateSpec {
+    pub secret_name: String,
+    /// Labels of the issued Secret, so that it is deleted with the NiFiDeployment
+    pub secret_template: Option<Value>,
+    pub common_name: Option<String>,
+    pub dns_names: Vec<String>,
+    pub subject: Option<CertificateSubject>,
+    pub usages: Option<Vec<String>>,
+    pub issuer_ref: IssuerRef,
+}
+
+/// Issuance state of a Certificate, the revision is incremented by every issuance
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CertificateStatus {
+    pub revision: Option<i64>,
+    pub not_after: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CertificateSubject {
+    pub organizational_units: Option<Vec<String>>,
+}
+
+/// Certificates of `tls.mode: certManager`.
+///
+/// Every NiFi node gets a Certificate named after its Pod and the ingress host gets its own one.
+/// Node certificates are issued into `<pod>-tls` Secrets, which NiFi Pods read themselves, so
+/// the operator never reads their private keys. The issuer has to provide the `ca.crt` key,
+/// which becomes the truststore.
+pub struct CertManagerController {
+    pub client: Rc<Client>,
+    pub template: Rc<Template>,
+}
+
+impl CertManagerController {
+    pub async fn handle_certificates(
+        &self,
+        name: &str,
+        ns: &str,
+        spec: &NiFiDeploymentSpec,
+        report: &StatusReport,
+    ) -> Result<TlsState> {
+        if spec.tls_mode() != Some(&TlsMode::CertManager) {
+            return Ok(TlsState {
+                updated: self.delete_certificates(name, ns).await?,
+                hash: None,
+            });
+        }
+        if !common_names_fit(name, ns, spec, report) {
+            return Ok(TlsState {
+                updated: false,
+                hash: None,
+            });
+        }
+        if spec
+            .tls
+            .as_ref()
+            .and_then(|t| t.issuer_ref.as_ref())
+            .is_none()
+        {
+            return Err(Error::msg("tls.issuerRef is required by certManager mode"));
+        }
+        let mut updated = false;
+        let mut revisions = Vec::new();
+        for ordinal in 0..spec.max_nifi_replicas() as i32 {
+            let pod = format!("{}-{}", name, ordinal);
+            let hosts = node_hosts(name, ns, ordinal, spec);
+            let (applied, status) = self.apply(name, ns, &pod, &hosts, spec).await?;
+            updated |= applied;
+            revisions.push(status.and_then(|s| {
+                s.revision
+                    .map(|r| format!("{}:{}", r, s.not_after.unwrap_or_default()))
+            }));
+        }
+        let ingress_name = format!("{}-ingress", name);
+        updated |= match &spec.ingress {
+            Some(ingress) => {
+                let hosts = vec![ingress.host.clone()];
+                self.apply(name, ns, &ingress_name, &hosts, spec).await?.0
+            }
+            None => delete_if_exists::<Certificate>(&self.client, ns, &ingress_name).await?,
+        };
+        let revisions = revisions.into_iter().collect::<Option<Vec<_>>>();
+        Ok(TlsState {
+            updated,
+            hash: revisions.map(|r| hash(&r.join(","))),
+        })
+    }
+
+    /// Creates or updates the Certificate, returns whether it was changed and its current status
+    async fn apply(
+        &self,
+        name: &str,
+        ns: &str,
+        certificate_name: &str,
+        hosts: &[String],
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<(bool, Option<CertificateStatus>)> {
+        let render = || {
+            self.template
+                .nifi_certificate(name, certificate_name, hosts, spec)
+        };
+        let certificate =
+            get_or_create::<Certificate, _>(&self.client, certificate_name, name, ns, |_| render())
+                .await?;
+        let current = match certificate {
+            Left(Some(current)) => current,
+            Right(Some(_)) => return Ok((true, None)),
+            _ => return Ok((false, None)),
+        };
+        let expected = match render()? {
+            Some(yaml) => from_yaml::<Certificate>(&yaml)?,
+            None => return Ok((false, None)),
+        };
+        let spec_json = |c: &Certificate| serde_json::to_value(&c.spec).unwrap_or_default();
+        if json_subset(&spec_json(&expected), &spec_json(&current)) {
+            return Ok((false, current.status));
+        }
+        debug!("Updating Certificate: {}", certificate_name);
+        let updated = Certificate {
+            spec: expected.spec,
+            ..current
+        };
+        get_api::<Certificate>(&self.client, ns)
+            .replace(certificate_name, &PostParams::default(), &updated)
+            .await?;
+        // the changed Certificate is issued again with a new revision
+        Ok((true, None))
+    }
+
+    /// Deletes Certificates of the NiFiDeployment and the Secrets issued for them, including
+    /// the ones issued without labels. Does nothing when cert-manager is not installed.
+    pub async fn delete_certificates(&self, name: &str, ns: &str) -> Result<bool> {
+        let lp = ListParams::default().labels(&instance_selector(name));
+        let certificates = match get_api::<Certificate>(&self.client, ns).list(&lp).await {
+            Ok(list) => list.items,
+            Err(kube::Error::Api(e)) if e.code == 404 => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        let mut deleted = false;
+        for certificate in certificates {
+            // the Secret is deleted after the Certificate, which would issue it again
+            let certificate_name = Meta::name(&certificate);
+            deleted |= delete_if_exists::<Certificate>(&self.client, ns, &certificate_name).await?;
+            let secret_name = &certificate.spec.secret_name;
+            deleted |= delete_if_exists::<Secret>(&self.client, ns, secret_name).await?;
+        }
+        Ok(deleted)
+    }
+}
+
+/// Secret of the ingress Certificate
+pub fn ingress_tls_secret(name: &str, spec: &NiFiDeploymentSpec) -> Option<String> {
+    if spec.tls_mode() == Some(&TlsMode::CertManager) && spec.ingress.is_some() {
+        Some(format!("{}-ingress-tls", name))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,175 @@
+use anyhow::{Error, Result};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::rand::rand_bytes;
+use openssl::rsa::Rsa;
+use openssl::x509::extension::{
+    AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+    SubjectKeyIdentifier,
+};
+use openssl::x509::{X509Builder, X509Name, X509NameBuilder, X509};
+
+const KEY_BITS: u32 = 2048;
+const CA_VALIDITY_DAYS: u32 = 3650;
+const NODE_VALIDITY_DAYS: u32 = 825;
+/// Subjects are "CN=<host>, OU=NIFI", which nifi.security.identity.mapping.pattern.dn maps to <host>
+const SUBJECT_OU: &str = "NIFI";
+/// Upper bound of the commonName attribute
+pub const MAX_CN_LENGTH: usize = 64;
+
+/// Self-signed CA, which issues the certificates of NiFi nodes
+pub struct CertificateAuthority {
+    pub cert: X509,
+    key: PKey<Private>,
+}
+
+impl CertificateAuthority {
+    pub fn generate(common_name: &str) -> Result<CertificateAuthority> {
+        let key = PKey::from_rsa(Rsa::generate(KEY_BITS)?)?;
+        let name = subject(common_name)?;
+        let mut builder = certificate(&name, &key, CA_VALIDITY_DAYS)?;
+        builder.set_issuer_name(&name)?;
+        builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+        builder.append_extension(
+            KeyUsage::new()
+                .critical()
+                .key_cert_sign()
+                .crl_sign()
+                .build()?,
+        )?;
+        let key_id = SubjectKeyIdentifier::new().build(&builder.x509v3_context(None, None))?;
+        builder.append_extension(key_id)?;
+        builder.sign(&key, MessageDigest::sha256())?;
+        Ok(CertificateAuthority {
+            cert: builder.build(),
+            key,
+        })
+    }
+
+    pub fn from_pem(cert: &[u8], key: &[u8]) -> Result<CertificateAuthority> {
+        Ok(CertificateAuthority {
+            cert: X509::from_pem(cert)?,
+            key: PKey::private_key_from_pem(key)?,
+        })
+    }
+
+    pub fn key_pem(&self) -> Result<Vec<u8>> {
+        Ok(self.key.private_key_to_pem_pkcs8()?)
+    }
+
+    /// Certificate for server and client authentication of a node. The first host is the
+    /// subject CN, all hosts are subject alternative names.
+    pub fn issue(&self, hosts: &[String]) -> Result<(X509, PKey<Private>)> {
+        let common_name = hosts
+            .first()
+            .ok_or_else(|| Error::msg("Certificate needs at least one host name"))?;
+        let key = PKey::from_rsa(Rsa::generate(KEY_BITS)?)?;
+        let mut builder = certificate(&subject(common_name)?, &key, NODE_VALIDITY_DAYS)?;
+        builder.set_issuer_name(self.cert.subject_name())?;
+        builder.append_extension(BasicConstraints::new().build()?)?;
+        builder.append_extension(
+            KeyUsage::new()
+                .critical()
+                .digital_signature()
+                .key_encipherment()
+                .build()?,
+        )?;
+        builder.append_extension(
+            ExtendedKeyUsage::new()
+                .server_auth()
+                .client_auth()
+                .build()?,
+        )?;
+        let mut san = SubjectAlternativeName::new();
+        for host in hosts {
+            san.dns(host);
+        }
+        let context = builder.x509v3_context(Some(&self.cert), None);
+        let san = san.build(&context)?;
+        let authority_key_id = AuthorityKeyIdentifier::new().keyid(false).build(&context)?;
+        builder.append_extension(san)?;
+        builder.append_extension(authority_key_id)?;
+        builder.sign(&self.key, MessageDigest::sha256())?;
+        Ok((builder.build(), key))
+    }
+}
+
+/// Whether the PEM certificate is invalid or expires within the given number of days
+pub fn expires_within(cert: &[u8], days: u32) -> bool {
+    let deadline = match Asn1Time::days_from_now(days) {
+        Ok(deadline) => deadline,
+        Err(_) => return true,
+    };
+    X509::from_pem(cert)
+        .map(|c| c.not_after() < deadline)
+        .unwrap_or(true)
+}
+
+/// Random password of 32 hex characters
+pub fn password() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    rand_bytes(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn subject(common_name: &str) -> Result<X509Name> {
+    if common_name.len() > MAX_CN_LENGTH {
+        return Err(Error::msg(format!(
+            "Host name {} is longer than {} characters allowed in certificate CN",
+            common_name, MAX_CN_LENGTH
+        )));
+    }
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::ORGANIZATIONALUNITNAME, SUBJECT_OU)?;
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+    Ok(name.build())
+}
+
+fn certificate(subject: &X509Name, key: &PKey<Private>, days: u32) -> Result<X509Builder> {
+    let mut serial = BigNum::new()?;
+    serial.rand(127, MsbOption::MAYBE_ZERO, false)?;
+    let serial = serial.to_asn1_integer()?;
+    let (not_before, not_after) = (Asn1Time::days_from_now(0)?, Asn1Time::days_from_now(days)?);
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(subject)?;
+    builder.set_pubkey(key)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_certificate_is_signed_by_ca() {
+        let ca = CertificateAuthority::generate("test-ca").unwrap();
+        let hosts = vec![
+            "test-0.test-headless.ns.svc.cluster.local".to_string(),
+            "nifi.example.com".to_string(),
+        ];
+        let (cert, key) = ca.issue(&hosts).unwrap();
+        assert!(cert.verify(&ca.cert.public_key().unwrap()).unwrap());
+        let sans = cert
+            .subject_alt_names()
+            .unwrap()
+            .iter()
+            .filter_map(|n| n.dnsname().map(String::from))
+            .collect::<Vec<_>>();
+        assert_eq!(sans, hosts);
+        assert!(!expires_within(&cert.to_pem().unwrap(), 30));
+        assert!(key.public_eq(&cert.public_key().unwrap()));
+    }
+
+    #[test]
+    fn long_host_name_is_rejected() {
+        let ca = CertificateAuthority::generate("test-ca").unwrap();
+        assert!(ca.issue(&["a".repeat(65)]).is_err());
+    }
+}
@@ -2,7 +2,7 @@ use std::rc::Rc;
 
 use anyhow::Result;
 use k8s_openapi::api::core::v1::ConfigMap;
-use kube::api::DeleteParams;
+use kube::api::{DeleteParams, PostParams};
 use kube::Client;
 
 use crate::controller::{create_from_yaml, from_yaml, get_api, get_or_create};
@@ -11,6 +11,8 @@ use crate::template::Template;
 
 use super::either::Either::{Left, Right};
 
+const AUTHORIZERS_KEY: &str = "authorizers.xml";
+
 pub struct ConfigMapController {
     pub client: Rc<Client>,
     pub template: Rc<Template>,
@@ -24,9 +26,15 @@ impl ConfigMapController {
         ns: &str,
     ) -> Result<bool> {
         let zk_cm_name = format!("{}-zookeeper", &name);
-        let zk_cm = get_or_create::<ConfigMap, _>(&self.client, &zk_cm_name, &name, &ns, |name| {
-            self.template.zk_configmap(name)
-        });
+        let zk_cm = async {
+            if !d.spec.managed_zookeeper() {
+                return Ok(Right(None));
+            }
+            get_or_create::<ConfigMap, _>(&self.client, &zk_cm_name, &name, &ns, |name| {
+                self.template.zk_configmap(name, &d.spec)
+            })
+            .await
+        };
 
         let nifi_cm_name = format!("{}-config", &name);
         let nifi_cm =
@@ -34,10 +42,14 @@ impl ConfigMapController {
                 self.template.nifi_configmap(name, &ns, &d.spec)
             });
 
-        let (r1, r2) = futures::future::join(zk_cm, nifi_cm).await;
-        let nifi_cm = r1.and(r2)?;
+        let (zk_cm, nifi_cm) = futures::future::join(zk_cm, nifi_cm).await;
+        if let Left(Some(existing_cm)) = zk_cm? {
+            let expected = self.template.zk_configmap(&name, &d.spec)?;
+            self.update_data(&ns, &zk_cm_name, existing_cm, expected)
+                .await?;
+        }
 
-        match nifi_cm {
+        match nifi_cm? {
             Left(maybe_cm) => match maybe_cm {
                 Some(existing_cm) => {
                     self.handle_update(&d, &name, &ns, &nifi_cm_name, existing_cm)
@@ -61,7 +73,7 @@ impl ConfigMapController {
         match maybe_yaml {
             Some(yaml) => {
                 let expected_cm = from_yaml::<ConfigMap>(&yaml)?;
-                let expected_data = expected_cm.data;
+                let expected_data = expected_cm.data.clone();
                 for (k1, v1) in expected_data.clone().unwrap_or_default() {
                     for (k2, v2) in current.clone().data.unwrap_or_default().clone() {
                         if k1 == k2 && v1 != v2 {
@@ -69,18 +81,52 @@ impl ConfigMapController {
                         }
                     }
                 }
-                if current.data != expected_data {
+                if current.data == expected_data {
+                    Ok(false)
+                } else if only_authorizers_changed(&current, &expected_cm) {
+                    // initial node identities are read only when NiFi has no users yet,
+                    // so running nodes are not restarted when the cluster is scaled
+                    self.update_data(&ns, &cm_name, current, Some(yaml)).await?;
+                    Ok(false)
+                } else if d.spec.canary().is_some() {
+                    // the change is tried on canary nodes with their own ConfigMap first
+                    Ok(false)
+                } else {
                     self.recreate_cm(&cr_name, &ns, &cm_name, &d)
                         .await
                         .map(|_| true)
-                } else {
-                    Ok(false)
                 }
             }
             None => Ok(false),
         }
     }
 
+    /// Replaces data of the existing ConfigMap, if it differs from the expected one
+    async fn update_data(
+        &self,
+        ns: &str,
+        cm_name: &str,
+        current: ConfigMap,
+        expected_yaml: Option<String>,
+    ) -> Result<bool> {
+        let expected_cm = match expected_yaml {
+            Some(yaml) => from_yaml::<ConfigMap>(&yaml)?,
+            None => return Ok(false),
+        };
+        if current.data == expected_cm.data {
+            return Ok(false);
+        }
+        debug!("Updating data of ConfigMap: {}", &cm_name);
+        let updated = ConfigMap {
+            data: expected_cm.data,
+            ..current
+        };
+        let api = get_api::<ConfigMap>(&self.client, &ns);
+        api.replace(&cm_name, &PostParams::default(), &updated)
+            .await?;
+        Ok(true)
+    }
+
     async fn recreate_cm(
         &self,
         name: &str,
@@ -104,3 +150,12 @@ impl ConfigMapController {
         .map(|_| ())
     }
 }
+
+fn only_authorizers_changed(current: &ConfigMap, expected: &ConfigMap) -> bool {
+    let without_authorizers = |cm: &ConfigMap| {
+        let mut data = cm.data.clone().unwrap_or_default();
+        data.remove(AUTHORIZERS_KEY);
+        data
+    };
+    without_authorizers(current) == without_authorizers(expected)
+}
@@ -0,0 +1,399 @@
+use anyhow::{Error, Result};
+use k8s_openapi::api::apps::v1::StatefulSet;
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::{PersistentVolume, PersistentVolumeClaim};
+use kube::api::{DeleteParams, ListParams, Meta, PatchParams, PostParams, PropagationPolicy};
+use kube::{Api, Client};
+
+use crate::controller::pvc::pvc_names;
+use crate::controller::{create_from_yaml, delete_resources, get_api, wait_deleted, StatusReport};
+use crate::crd::{NiFiDeployment, StorageMigration};
+use crate::template::Template;
+
+pub const STORAGE_MIGRATION_CONDITION: &str = "StorageMigration";
+
+/// Label of the PersistentVolumes with copied data, value is the StatefulSet name
+const MIGRATION_LABEL: &str = "kubefi.io/migration";
+/// `<namespace>/<name>` of the PVC which has to be bound to the migrated PersistentVolume
+const MIGRATION_CLAIM_ANNOTATION: &str = "kubefi.io/migration-claim";
+const RECLAIM_POLICY_ANNOTATION: &str = "kubefi.io/reclaim-policy";
+const MIGRATION_CLAIM_SUFFIX: &str = "migration";
+const RETAIN_POLICY: &str = "Retain";
+
+/// Copies data of every StatefulSet replica to volumes with the new storage class or access modes.
+/// Each step is checked again on the next event, so the migration continues across resyncs.
+pub struct VolumeMigration<'a> {
+    pub client: &'a Client,
+    pub template: &'a Template,
+    pub d: &'a NiFiDeployment,
+    pub name: &'a str,
+    pub ns: &'a str,
+}
+
+impl<'a> VolumeMigration<'a> {
+    /// Returns true once all volumes are switched and the StatefulSet can be recreated
+    pub async fn migrate(
+        &self,
+        current: &StatefulSet,
+        expected: &StatefulSet,
+        report: &StatusReport,
+    ) -> Result<bool> {
+        let set_name = Meta::name(current);
+        if !self.stop_pods(current, &set_name, report).await? {
+            return Ok(false);
+        }
+        let replicas = expected.spec.as_ref().and_then(|s| s.replicas).unwrap_or(0);
+        if !self
+            .copy_volumes(&set_name, replicas, expected, report)
+            .await?
+        {
+            return Ok(false);
+        }
+        self.release_copies(&set_name, replicas, expected).await?;
+        self.switch_volumes(&set_name, expected).await?;
+        Ok(true)
+    }
+
+    /// Removes the copy Jobs after the StatefulSet has been recreated with the new volumes
+    pub async fn complete(&self, set_name: &str, report: &StatusReport) -> Result<()> {
+        let dp = DeleteParams {
+            propagation_policy: Some(PropagationPolicy::Background),
+            ..DeleteParams::default()
+        };
+        let lp = ListParams::default().labels(&format!("app={}", copy_job_label(set_name)));
+        delete_resources::<Job>(self.client, self.ns, &dp, &lp).await?;
+        let msg = format!("Volumes of {} are migrated", set_name);
+        report.set(STORAGE_MIGRATION_CONDITION, false, "Completed", &msg);
+        Ok(())
+    }
+
+    async fn stop_pods(
+        &self,
+        current: &StatefulSet,
+        set_name: &str,
+        report: &StatusReport,
+    ) -> Result<bool> {
+        let replicas = current.spec.as_ref().and_then(|s| s.replicas).unwrap_or(0);
+        let running = current.status.as_ref().map(|s| s.replicas).unwrap_or(0);
+        if replicas > 0 {
+            debug!("Scaling {} down to migrate its volumes", set_name);
+            let api = get_api::<StatefulSet>(self.client, self.ns);
+            let patch = json!({ "spec": { "replicas": 0 }});
+            api.patch(
+                set_name,
+                &PatchParams::default(),
+                serde_json::to_vec(&patch)?,
+            )
+            .await?;
+        }
+        if replicas > 0 || running > 0 {
+            let msg = format!("Waiting for Pods of {} to stop", set_name);
+            report.set(STORAGE_MIGRATION_CONDITION, true, "StoppingPods", &msg);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Starts a copy Job per replica and returns true when all of them succeeded.
+    /// Copy claims are created only together with their Job, so a copy which is already
+    /// released after its Job succeeded is never replaced by an empty one.
+    async fn copy_volumes(
+        &self,
+        set_name: &str,
+        replicas: i32,
+        expected: &StatefulSet,
+        report: &StatusReport,
+    ) -> Result<bool> {
+        let job_api = get_api::<Job>(self.client, self.ns);
+        let (mut copied, mut total) = (0, 0);
+        for ordinal in 0..replicas {
+            let job_name = format!("{}-{}", copy_job_label(set_name), ordinal);
+            let job = match job_api.get(&job_name).await {
+                Ok(job) => Some(job),
+                Err(kube::Error::Api(e)) if e.code == 404 => {
+                    self.start_copy(set_name, ordinal, expected).await?
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let job = match job {
+                Some(job) => job,
+                None => continue,
+            };
+            total += 1;
+            if job_failed(&job) {
+                let msg = format!("Job {} failed to copy volumes of {}", job_name, set_name);
+                report.set(STORAGE_MIGRATION_CONDITION, true, "CopyFailed", &msg);
+                return Err(Error::msg(msg));
+            }
+            if job_succeeded(&job) {
+                copied += 1;
+            }
+        }
+        let msg = format!(
+            "{} of {} replicas of {} have their volumes copied",
+            copied, total, set_name
+        );
+        report.set(STORAGE_MIGRATION_CONDITION, true, "CopyingVolumes", &msg);
+        Ok(copied == total)
+    }
+
+    /// Creates copy claims of the replica volumes and the Job copying them
+    async fn start_copy(
+        &self,
+        set_name: &str,
+        ordinal: i32,
+        expected: &StatefulSet,
+    ) -> Result<Option<Job>> {
+        let pvc_api = get_api::<PersistentVolumeClaim>(self.client, self.ns);
+        let mut volumes = Vec::new();
+        for claim in claim_templates(expected) {
+            let claim_name = Meta::name(&claim);
+            let source = pvc_name(&claim_name, set_name, ordinal);
+            if pvc_api.get(&source).await.is_ok() {
+                self.create_copy_claim(&pvc_api, &claim, &source).await?;
+                volumes.push(claim_name);
+            }
+        }
+        if volumes.is_empty() {
+            return Ok(None);
+        }
+        let job = create_from_yaml::<Job, _, _>(
+            self.name,
+            self.ns,
+            self.client,
+            |_| {
+                self.template
+                    .volume_copy_job(self.name, &self.d.spec, set_name, ordinal, &volumes)
+            },
+            Ok,
+        )
+        .await?;
+        Ok(job.into_inner())
+    }
+
+    async fn create_copy_claim(
+        &self,
+        api: &Api<PersistentVolumeClaim>,
+        claim: &PersistentVolumeClaim,
+        source: &str,
+    ) -> Result<()> {
+        let name = copy_claim_name(source);
+        if api.get(&name).await.is_ok() {
+            return Ok(());
+        }
+        let mut copy = claim.clone();
+        copy.metadata.name = Some(name);
+        debug!("Creating PVC {} for data of {}", Meta::name(&copy), source);
+        api.create(&PostParams::default(), &copy).await?;
+        Ok(())
+    }
+
+    /// Keeps PersistentVolumes of the copies and marks them for the original PVC names
+    async fn release_copies(
+        &self,
+        set_name: &str,
+        replicas: i32,
+        expected: &StatefulSet,
+    ) -> Result<()> {
+        let pvc_api = get_api::<PersistentVolumeClaim>(self.client, self.ns);
+        let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+        // claims with a released copy, which are skipped when the release is retried
+        let lp = ListParams::default().labels(&format!("{}={}", MIGRATION_LABEL, set_name));
+        let released = pv_api
+            .list(&lp)
+            .await?
+            .into_iter()
+            .filter_map(|pv| pv.metadata.annotations?.remove(MIGRATION_CLAIM_ANNOTATION))
+            .collect::<Vec<_>>();
+        for claim in claim_templates(expected) {
+            for target in pvc_names(&Meta::name(&claim), set_name, replicas) {
+                if released.contains(&format!("{}/{}", self.ns, target)) {
+                    continue;
+                }
+                let copy = match pvc_api.get(&copy_claim_name(&target)).await {
+                    Ok(copy) => copy,
+                    Err(_) => continue,
+                };
+                let copy_name = Meta::name(&copy);
+                let pv_name = copy
+                    .spec
+                    .and_then(|s| s.volume_name)
+                    .ok_or_else(|| Error::msg(format!("PVC {} is not bound", &copy_name)))?;
+                let pv = pv_api.get(&pv_name).await?;
+                let reclaim_policy = pv.spec.and_then(|s| s.persistent_volume_reclaim_policy);
+                let patch = json!({
+                    "metadata": {
+                        "labels": { MIGRATION_LABEL: set_name },
+                        "annotations": {
+                            MIGRATION_CLAIM_ANNOTATION: format!("{}/{}", self.ns, target),
+                            RECLAIM_POLICY_ANNOTATION: reclaim_policy
+                        }
+                    },
+                    "spec": { "persistentVolumeReclaimPolicy": RETAIN_POLICY }
+                });
+                pv_api
+                    .patch(
+                        &pv_name,
+                        &PatchParams::default(),
+                        serde_json::to_vec(&patch)?,
+                    )
+                    .await?;
+                pvc_api.delete(&copy_name, &DeleteParams::default()).await?;
+                wait_deleted(&pvc_api, &copy_name).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the original PVCs by the ones bound to the copied PersistentVolumes
+    async fn switch_volumes(&self, set_name: &str, expected: &StatefulSet) -> Result<()> {
+        let pvc_api = get_api::<PersistentVolumeClaim>(self.client, self.ns);
+        let pv_api: Api<PersistentVolume> = Api::all(self.client.clone());
+        let lp = ListParams::default().labels(&format!("{}={}", MIGRATION_LABEL, set_name));
+        let claims = claim_templates(expected);
+        for pv in pv_api.list(&lp).await? {
+            let annotations = pv.metadata.annotations.clone().unwrap_or_default();
+            let target = match annotations
+                .get(MIGRATION_CLAIM_ANNOTATION)
+                .and_then(|c| c.strip_prefix(&format!("{}/", self.ns)))
+            {
+                Some(target) => target.to_string(),
+                None => continue,
+            };
+            let claim = claims
+                .iter()
+                .find(|c| target.starts_with(&format!("{}-{}-", Meta::name(*c), set_name)))
+                .ok_or_else(|| Error::msg(format!("No volume claim template for {}", target)))?;
+            let pv_name = Meta::name(&pv);
+
+            if let Ok(old) = pvc_api.get(&target).await {
+                if old.spec.and_then(|s| s.volume_name).as_ref() != Some(&pv_name) {
+                    debug!("Deleting PVC {} to bind it to {}", &target, &pv_name);
+                    pvc_api.delete(&target, &DeleteParams::default()).await?;
+                    wait_deleted(&pvc_api, &target).await?;
+                }
+            }
+            if pvc_api.get(&target).await.is_err() {
+                let patch = json!({ "spec": { "claimRef": {
+                    "namespace": self.ns,
+                    "name": &target,
+                    "uid": null,
+                    "resourceVersion": null
+                }}});
+                pv_api
+                    .patch(
+                        &pv_name,
+                        &PatchParams::default(),
+                        serde_json::to_vec(&patch)?,
+                    )
+                    .await?;
+                let mut pvc = claim.clone();
+                pvc.metadata.name = Some(target.clone());
+                if let Some(spec) = pvc.spec.as_mut() {
+                    spec.volume_name = Some(pv_name.clone());
+                }
+                debug!("Creating PVC {} bound to {}", &target, &pv_name);
+                pvc_api.create(&PostParams::default(), &pvc).await?;
+            }
+
+            let patch = json!({
+                "metadata": {
+                    "labels": { MIGRATION_LABEL: null },
+                    "annotations": {
+                        MIGRATION_CLAIM_ANNOTATION: null,
+                        RECLAIM_POLICY_ANNOTATION: null
+                    }
+                },
+                "spec": { "persistentVolumeReclaimPolicy": annotations.get(RECLAIM_POLICY_ANNOTATION) }
+            });
+            pv_api
+                .patch(
+                    &pv_name,
+                    &PatchParams::default(),
+                    serde_json::to_vec(&patch)?,
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Default mode: existing volumes are kept until the migration is allowed in the CR
+pub fn refuse_migration(set_name: &str, report: &StatusReport) {
+    let msg = format!(
+        "Storage class or access modes of {} volumes changed. \
+         Set spec.storageMigration to Copy to migrate the data, \
+         existing volumes are kept until then",
+        set_name
+    );
+    report.set(STORAGE_MIGRATION_CONDITION, false, "Refused", &msg);
+}
+
+/// Clears refused migration once the volumes match the CR again
+pub fn migration_up_to_date(report: &StatusReport) {
+    let refused = report
+        .previous(STORAGE_MIGRATION_CONDITION)
+        .map(|c| c.reason == "Refused")
+        .unwrap_or(false);
+    if refused && !report.reported(STORAGE_MIGRATION_CONDITION) {
+        report.set(
+            STORAGE_MIGRATION_CONDITION,
+            false,
+            "UpToDate",
+            "Volumes match the storage settings",
+        );
+    }
+}
+
+pub fn storage_migration(d: &NiFiDeployment) -> StorageMigration {
+    d.spec
+        .storage_migration
+        .clone()
+        .unwrap_or(StorageMigration::Refuse)
+}
+
+/// Keeps the volume claims of the current StatefulSet in the expected one
+pub fn keep_volume_claims(current: &StatefulSet, expected: &mut StatefulSet) {
+    let claims = current
+        .spec
+        .as_ref()
+        .and_then(|s| s.volume_claim_templates.clone());
+    if let Some(spec) = expected.spec.as_mut() {
+        spec.volume_claim_templates = claims;
+    }
+}
+
+fn claim_templates(set: &StatefulSet) -> Vec<PersistentVolumeClaim> {
+    set.spec
+        .as_ref()
+        .and_then(|s| s.volume_claim_templates.clone())
+        .unwrap_or_default()
+}
+
+fn pvc_name(claim: &str, set_name: &str, ordinal: i32) -> String {
+    format!("{}-{}-{}", claim, set_name, ordinal)
+}
+
+fn copy_claim_name(pvc_name: &str) -> String {
+    format!("{}-{}", pvc_name, MIGRATION_CLAIM_SUFFIX)
+}
+
+fn copy_job_label(set_name: &str) -> String {
+    format!("{}-volume-copy", set_name)
+}
+
+pub fn job_succeeded(job: &Job) -> bool {
+    job.status.as_ref().and_then(|s| s.succeeded).unwrap_or(0) > 0
+}
+
+pub fn job_failed(job: &Job) -> bool {
+    job.status
+        .as_ref()
+        .and_then(|s| s.conditions.as_ref())
+        .map(|conditions| {
+            conditions
+                .iter()
+                .any(|c| c.type_ == "Failed" && c.status == "True")
+        })
+        .unwrap_or(false)
+}
@@ -4,39 +4,76 @@ extern crate kube;
 extern crate kube_derive;
 extern crate serde;
 
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::rc::Rc;
 use std::{error, fmt};
 
 use anyhow::Error;
+use chrono::{SecondsFormat, Utc};
 use k8s_openapi::api::apps::v1::StatefulSet;
-use k8s_openapi::api::core::v1::{ConfigMap, Service};
+use k8s_openapi::api::autoscaling::v2beta2::HorizontalPodAutoscaler;
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::{ConfigMap, Secret, Service, ServiceAccount};
 use k8s_openapi::api::extensions::v1beta1::Ingress;
+use k8s_openapi::api::rbac::v1::{Role, RoleBinding};
 use k8s_openapi::Resource;
 use kube::api::{DeleteParams, ListParams, Meta, PostParams};
 use kube::{Api, Client};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
+use serde_json::Value;
+use tokio::time::{delay_for, Duration};
 
 use crate::anyhow::Result;
+use crate::controller::autoscaler::AutoscalerController;
+use crate::controller::cert_manager::CertManagerController;
 use crate::controller::configmap::ConfigMapController;
+use crate::controller::nifi_api::NiFiApi;
+use crate::controller::rbac::RbacController;
+use crate::controller::revision::{RevisionController, Revisions, ROLLBACK_CONDITION};
+use crate::controller::schedule::apply_schedule;
 use crate::controller::service::ServiceController;
 use crate::controller::statefulset::StatefulSetController;
+use crate::controller::tls::{instance_selector, TlsController};
+use crate::controller::zookeeper::{auth_secret_name, ZK_DELETED_CONDITION};
 use crate::controller::ControllerError::MissingProperty;
-use crate::crd::{NiFiDeployment, NiFiDeploymentStatus};
-use crate::template::Template;
+use crate::crd::{Condition, NiFiDeployment, NiFiDeploymentSpec, NiFiDeploymentStatus, TlsMode};
+use crate::metrics::{ClusterMetrics, Metrics};
+use crate::template::{default_zk_root_node, Template};
 use crate::{read_type, Namespace};
 
 use self::either::Either;
 use self::either::Either::{Left, Right};
 
+mod autoscaler;
+mod canary;
+mod cert_manager;
+mod certificates;
 mod configmap;
+mod migration;
+mod nifi_api;
+mod pvc;
+mod rbac;
+mod revision;
+mod scaling;
+mod schedule;
 mod service;
 mod statefulset;
+mod suspension;
+mod tls;
+mod upgrade;
+mod zookeeper;
 
 const KUBEFI_LABELS: &str = "app.kubernetes.io/managed-by=Kubefi,release=nifi";
 const NIFI_APP_LABEL: &str = "nifi";
 const ZK_APP_LABEL: &str = "zookeeper";
+const DELETE_WAIT_ATTEMPTS: u32 = 30;
+/// ZooKeeper root node of clusters created before root nodes were unique per cluster
+const LEGACY_ZK_ROOT_NODE: &str = "/nifi";
+/// False while the default ZooKeeper root node cannot be determined, nothing is applied then
+pub const ZK_ROOT_NODE_CONDITION: &str = "ZooKeeperRootNode";
 
 #[derive(Debug)]
 pub enum ControllerError {
@@ -76,12 +113,86 @@ pub struct NiFiController {
     cm_controller: ConfigMapController,
     svc_controller: ServiceController,
     sets_controller: StatefulSetController,
+    rbac_controller: RbacController,
+    autoscaler_controller: AutoscalerController,
+    revision_controller: RevisionController,
+    tls_controller: TlsController,
+    cert_manager_controller: CertManagerController,
+    template: Rc<Template>,
+    metrics: Metrics,
+    /// NiFi API clients by namespace and name, so that metrics are collected without a login
+    /// on every reconciliation
+    nifi_apis: RefCell<BTreeMap<(String, String), Rc<NiFiApi>>>,
 }
 
 #[derive(Clone, Debug)]
 pub struct ConfigMapState {
     pub updated: bool,
     pub logging_cm: Option<String>,
+    /// Hash of node certificates, NiFi Pods are restarted when it changes
+    pub tls_hash: Option<String>,
+}
+
+/// Status conditions reported by the resource controllers during a single event handling.
+/// Conditions which are not reported again keep their previous state.
+pub struct StatusReport {
+    previous: Vec<Condition>,
+    current: RefCell<Vec<Condition>>,
+}
+
+impl StatusReport {
+    fn new(status: &Option<NiFiDeploymentStatus>) -> StatusReport {
+        StatusReport {
+            previous: status.clone().map(|s| s.conditions).unwrap_or_default(),
+            current: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn previous(&self, condition_type: &str) -> Option<&Condition> {
+        self.previous
+            .iter()
+            .find(|c| c.condition_type == condition_type)
+    }
+
+    pub fn set(&self, condition_type: &str, status: bool, reason: &str, message: &str) {
+        let status = if status { "True" } else { "False" }.to_string();
+        let last_transition_time = self
+            .previous(condition_type)
+            .filter(|c| c.status == status)
+            .map(|c| c.last_transition_time.clone())
+            .unwrap_or_else(|| Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+        let condition = Condition {
+            condition_type: condition_type.to_string(),
+            status,
+            reason: reason.to_string(),
+            message: message.to_string(),
+            last_transition_time,
+        };
+        let mut current = self.current.borrow_mut();
+        current.retain(|c| c.condition_type != condition_type);
+        current.push(condition);
+    }
+
+    /// Whether the condition has already been set during this event handling
+    pub fn reported(&self, condition_type: &str) -> bool {
+        self.current
+            .borrow()
+            .iter()
+            .any(|c| c.condition_type == condition_type)
+    }
+
+    fn conditions(&self) -> Vec<Condition> {
+        let current = self.current.borrow();
+        let mut conditions = self
+            .previous
+            .iter()
+            .filter(|p| current.iter().all(|c| c.condition_type != p.condition_type))
+            .cloned()
+            .collect::<Vec<_>>();
+        conditions.extend(current.iter().cloned());
+        conditions.sort_by(|a, b| a.condition_type.cmp(&b.condition_type));
+        conditions
+    }
 }
 
 impl NiFiController {
@@ -89,6 +200,7 @@ impl NiFiController {
         ns: Namespace,
         client: Rc<Client>,
         template: Rc<Template>,
+        metrics: Metrics,
     ) -> Result<NiFiController> {
         let cm_controller = ConfigMapController {
             client: client.clone(),
@@ -100,7 +212,26 @@ impl NiFiController {
         };
         let sets_controller = StatefulSetController {
             client: client.clone(),
-            template,
+            template: template.clone(),
+        };
+        let rbac_controller = RbacController {
+            client: client.clone(),
+            template: template.clone(),
+        };
+        let autoscaler_controller = AutoscalerController {
+            client: client.clone(),
+            template: template.clone(),
+        };
+        let revision_controller = RevisionController {
+            client: client.clone(),
+            template: template.clone(),
+        };
+        let tls_controller = TlsController {
+            client: client.clone(),
+        };
+        let cert_manager_controller = CertManagerController {
+            client: client.clone(),
+            template: template.clone(),
         };
         Ok(NiFiController {
             namespace: ns,
@@ -108,25 +239,112 @@ impl NiFiController {
             cm_controller,
             svc_controller,
             sets_controller,
+            rbac_controller,
+            autoscaler_controller,
+            revision_controller,
+            tls_controller,
+            cert_manager_controller,
+            template,
+            metrics,
+            nifi_apis: RefCell::new(BTreeMap::new()),
         })
     }
 
     pub async fn on_apply(&self, d: NiFiDeployment) -> Result<Option<ReplaceStatus>> {
         let name = read_name(&d)?;
         let ns = read_namespace(&d)?;
-        let status = match self.handle_event(d.clone(), &name, &ns).await {
-            Ok(true) => {
+        let report = StatusReport::new(&d.status);
+        let rollback = self
+            .revision_controller
+            .rollback(&d, &name, &ns, &report)
+            .await;
+        let mut d = match rollback {
+            Ok(Some(updated)) => updated,
+            Ok(None) => d,
+            Err(e) => {
+                report.set(ROLLBACK_CONDITION, false, "Failed", &e.to_string());
+                d
+            }
+        };
+        // revisions keep the spec as applied by the user, without scheduled replicas
+        let applied_spec = d.spec.clone();
+        let active_profile = apply_schedule(&mut d, &report, Utc::now());
+        let zk_root_node = match self.zk_root_node(&d, &name, &ns).await {
+            Ok(node) => {
+                if report.previous(ZK_ROOT_NODE_CONDITION).is_some() {
+                    let msg = format!("ZooKeeper root node is {}", node);
+                    report.set(ZK_ROOT_NODE_CONDITION, true, "Resolved", &msg);
+                }
+                node
+            }
+            Err(e) => {
+                let msg = format!("Failed to determine ZooKeeper root node: {}", e);
+                report.set(ZK_ROOT_NODE_CONDITION, false, "Failed", &msg);
+                // retried on the next resync
                 let status = NiFiDeploymentStatus {
-                    nifi_replicas: d.spec.nifi_replicas,
+                    error_msg: msg,
+                    conditions: report.conditions(),
+                    ..d.status.clone().unwrap_or_default()
+                };
+                return Ok(Some(ReplaceStatus { name, ns, status }));
+            }
+        };
+        if d.spec.zk.root_node.is_none() {
+            d.spec.zk.root_node = Some(zk_root_node.clone());
+        }
+        let result = match self.handle_event(d.clone(), &name, &ns, &report).await {
+            Ok(updated) => self
+                .revision_controller
+                .record(&d, &applied_spec, &name, &ns)
+                .await
+                .map(|revisions| (updated, revisions)),
+            Err(e) => Err(e),
+        };
+        let nifi_replicas = match d.spec.autoscaling {
+            Some(_) => self.nifi_replicas(&name, &ns).await,
+            None => d.spec.nifi_replicas as i32,
+        };
+        self.collect_metrics(&d, &name, &ns, nifi_replicas).await;
+        let selector = format!(
+            "app={},release=nifi,app.kubernetes.io/instance={}",
+            NIFI_APP_LABEL, &name
+        );
+        let status = match result {
+            Ok((updated, revisions)) => {
+                let status = NiFiDeploymentStatus {
+                    nifi_replicas: nifi_replicas as u8,
+                    selector,
+                    active_profile,
+                    current_revision: revisions.current,
+                    previous_revision: revisions.previous,
+                    zk_root_node: Some(zk_root_node),
                     error_msg: "".to_string(),
+                    conditions: report.conditions(),
                 };
-                Some(ReplaceStatus { name, ns, status })
+                if updated || d.status.as_ref() != Some(&status) {
+                    Some(ReplaceStatus { name, ns, status })
+                } else {
+                    None
+                }
             }
-            Ok(_) => None,
             Err(e) => {
+                let revisions = d
+                    .status
+                    .as_ref()
+                    .map(|s| Revisions {
+                        current: s.current_revision,
+                        previous: s.previous_revision,
+                    })
+                    .unwrap_or_default();
                 let status = NiFiDeploymentStatus {
-                    nifi_replicas: d.spec.nifi_replicas,
+                    nifi_replicas: nifi_replicas as u8,
+                    selector,
+                    active_profile,
+                    current_revision: revisions.current,
+                    previous_revision: revisions.previous,
+                    zk_root_node: Some(zk_root_node),
                     error_msg: e.to_string(),
+                    conditions: report.conditions(),
                 };
                 Some(ReplaceStatus { name, ns, status })
             }
@@ -134,17 +352,130 @@ impl NiFiController {
         Ok(status)
     }
 
+    /// ZooKeeper root node used while zk.rootNode is unset. Clusters which already have a NiFi
+    /// ConfigMap were created with /nifi and keep it, so that their cluster state is not lost.
+    async fn zk_root_node(&self, d: &NiFiDeployment, name: &str, ns: &str) -> Result<String> {
+        if let Some(node) = d.status.as_ref().and_then(|s| s.zk_root_node.clone()) {
+            return Ok(node);
+        }
+        let cm_name = format!("{}-config", name);
+        match get_api::<ConfigMap>(&self.client, ns).get(&cm_name).await {
+            Ok(_) => Ok(LEGACY_ZK_ROOT_NODE.to_string()),
+            Err(kube::Error::Api(e)) if e.code == 404 => Ok(default_zk_root_node(name, ns)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Pods of the NiFi StatefulSet, reported as status replicas of the scale subresource
+    async fn nifi_replicas(&self, name: &str, ns: &str) -> i32 {
+        let api = get_api::<StatefulSet>(&self.client, ns);
+        api.get(name)
+            .await
+            .ok()
+            .and_then(|set| set.status)
+            .map(|s| s.replicas)
+            .unwrap_or(0)
+    }
+
+    /// Records NiFi flow metrics of a NiFiDeployment with autoscaling. Metrics which NiFi API
+    /// does not return are not exported, so the HPA does not scale on them.
+    async fn collect_metrics(&self, d: &NiFiDeployment, name: &str, ns: &str, replicas: i32) {
+        let key = (ns.to_string(), name.to_string());
+        if d.spec.autoscaling.is_none() {
+            self.metrics.remove(ns, name);
+            self.nifi_apis.borrow_mut().remove(&key);
+            return;
+        }
+        let mut metrics = ClusterMetrics {
+            replicas,
+            ..ClusterMetrics::default()
+        };
+        if replicas > 0 {
+            let flow = async {
+                let api = self.nifi_api(name, ns, &d.spec).await?;
+                let (flow_files, bytes) = api.queued().await?;
+                let backpressured = api.backpressured_connections().await?;
+                Ok::<_, Error>((flow_files, bytes, backpressured))
+            };
+            match flow.await {
+                Ok((flow_files, bytes, backpressured)) => {
+                    metrics.flow_files_queued = Some(flow_files);
+                    metrics.bytes_queued = Some(bytes);
+                    metrics.backpressured_connections = Some(backpressured);
+                }
+                Err(e) => {
+                    // the token may have expired, the next reconciliation logs in again
+                    self.nifi_apis.borrow_mut().remove(&key);
+                    warn!("Failed to collect metrics of {}/{}: {}", ns, name, e)
+                }
+            }
+        }
+        self.metrics.record(ns, name, metrics);
+    }
+
+    /// NiFi API client of the cluster, connected once and reused while the API URL stays the same
+    async fn nifi_api(
+        &self,
+        name: &str,
+        ns: &str,
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<Rc<NiFiApi>> {
+        let key = (ns.to_string(), name.to_string());
+        let url = self.template.nifi_api_url(name, ns, spec);
+        let cached = self
+            .nifi_apis
+            .borrow()
+            .get(&key)
+            .filter(|api| api.url() == url)
+            .cloned();
+        if let Some(api) = cached {
+            return Ok(api);
+        }
+        let api = Rc::new(NiFiApi::connect(&self.client, name, ns, &url, spec).await?);
+        self.nifi_apis.borrow_mut().insert(key, api.clone());
+        Ok(api)
+    }
+
     pub async fn on_delete(&self, d: NiFiDeployment) -> Result<()> {
         let ns = read_namespace(&d)?;
+        let name = read_name(&d)?;
+        self.metrics.remove(&ns, &name);
+        self.nifi_apis
+            .borrow_mut()
+            .remove(&(ns.clone(), name.clone()));
         let params = &DeleteParams::default();
         let lp = ListParams::default().labels(KUBEFI_LABELS);
+        let instance_lp = ListParams::default().labels(&instance_selector(&name));
 
-        let sts = self.delete_resources::<StatefulSet>(&ns, &params, &lp);
-        let svc = self.delete_resources::<Service>(&ns, &params, &lp);
-        let cm = self.delete_resources::<ConfigMap>(&ns, &params, &lp);
-        let ing = self.delete_resources::<Ingress>(&ns, &params, &lp);
-        let (r1, r2, r3, r4) = futures::future::join4(sts, svc, cm, ing).await;
-        r1.and(r2).and(r3).and(r4)
+        let sts = self.delete_resources::<StatefulSet>(&ns, params, &lp);
+        let svc = self.delete_resources::<Service>(&ns, params, &lp);
+        let cm = self.delete_resources::<ConfigMap>(&ns, params, &lp);
+        let ing = self.delete_resources::<Ingress>(&ns, params, &lp);
+        let jobs = self.delete_resources::<Job>(&ns, params, &instance_lp);
+        let (r1, r2, r3, r4, r5) = futures::future::join5(sts, svc, cm, ing, jobs).await;
+        // the rest is deleted by this instance only, other NiFiDeployments of the namespace keep theirs
+        let sa = delete_if_exists::<ServiceAccount>(&self.client, &ns, &name);
+        let role = delete_if_exists::<Role>(&self.client, &ns, &name);
+        let binding = delete_if_exists::<RoleBinding>(&self.client, &ns, &name);
+        let hpa = delete_if_exists::<HorizontalPodAutoscaler>(&self.client, &ns, &name);
+        let secrets = self.delete_resources::<Secret>(&ns, params, &instance_lp);
+        let (r6, r7, r8, r9, r10) = futures::future::join5(sa, role, binding, hpa, secrets).await;
+        let r11 = self
+            .cert_manager_controller
+            .delete_certificates(&name, &ns)
+            .await
+            .map(|_| ());
+        r1.and(r2)
+            .and(r3)
+            .and(r4)
+            .and(r5)
+            .and(r6)
+            .and(r7)
+            .and(r8)
+            .and(r9)
+            .map(|_| ())
+            .and(r10)
+            .and(r11)
     }
 
     async fn delete_resources<T: Resource + Clone + DeserializeOwned + Meta + Debug>(
@@ -170,25 +501,106 @@ impl NiFiController {
             .fold(Ok(()), |acc, r| acc.and(r.map_err(Error::from)))
     }
 
-    async fn handle_event(&self, d: NiFiDeployment, name: &str, ns: &str) -> Result<bool> {
+    async fn handle_event(
+        &self,
+        d: NiFiDeployment,
+        name: &str,
+        ns: &str,
+        report: &StatusReport,
+    ) -> Result<bool> {
         let nifi_cm_updated = self.cm_controller.handle_configmaps(&d, &name, &ns).await?;
+        let rbac_updated = self.rbac_controller.handle_rbac(name, ns, &d.spec).await?;
+        let service_updated = self
+            .svc_controller
+            .handle_services(&name, &ns, &d.spec)
+            .await?;
+        let generate = self.tls_controller.handle_tls(name, ns, &d.spec, report);
+        let issue = self
+            .cert_manager_controller
+            .handle_certificates(name, ns, &d.spec, report);
+        // both modes use the same Secret names, so the former mode deletes its Secrets first
+        let (generated, issued) = if d.spec.tls_mode() == Some(&TlsMode::Generate) {
+            let issued = issue.await?;
+            (generate.await?, issued)
+        } else {
+            let generated = generate.await?;
+            (generated, issue.await?)
+        };
+        let tls_updated = generated.updated | issued.updated;
         let cm_state = ConfigMapState {
             updated: nifi_cm_updated,
             logging_cm: d.clone().spec.logging_config_map,
+            tls_hash: generated.hash.or(issued.hash),
         };
-        let service_updated = self
-            .svc_controller
-            .handle_services(&name, &ns, &d.spec.ingress)
-            .await?;
         let sets_updated = self
             .sets_controller
-            .handle_sets(&d, &name, &ns, cm_state, service_updated)
+            .handle_sets(&d, &name, &ns, cm_state, service_updated, report)
+            .await?;
+        let hpa_updated = self
+            .autoscaler_controller
+            .handle_autoscaler(name, ns, &d.spec)
             .await?;
+        let zk_deleted = self.handle_zookeeper_removal(&d, name, ns, report).await?;
         debug!(
-            "Resource updates: configmap = {}, statefulsets = {}, services = {}",
-            nifi_cm_updated, sets_updated, service_updated
+            "Resource updates: configmap = {}, statefulsets = {}, services = {}, rbac = {}, \
+            tls = {}, hpa = {}, zookeeper deleted = {}",
+            nifi_cm_updated,
+            sets_updated,
+            service_updated,
+            rbac_updated,
+            tls_updated,
+            hpa_updated,
+            zk_deleted
         );
-        Ok(nifi_cm_updated || sets_updated || service_updated)
+        Ok(nifi_cm_updated
+            || sets_updated
+            || service_updated
+            || tls_updated
+            || rbac_updated
+            || hpa_updated
+            || zk_deleted)
+    }
+
+    /// Removes ZooKeeper deployed by Kubefi once, when NiFi switches to Kubernetes coordination.
+    /// With an external ZooKeeper the deployed one is kept with its state, it is no longer updated.
+    async fn handle_zookeeper_removal(
+        &self,
+        d: &NiFiDeployment,
+        name: &str,
+        ns: &str,
+        report: &StatusReport,
+    ) -> Result<bool> {
+        let deleted = report
+            .previous(ZK_DELETED_CONDITION)
+            .map(|c| c.status == "True")
+            .unwrap_or(false);
+        if !d.spec.kubernetes_coordination() {
+            if deleted {
+                let msg = "NiFi uses ZooKeeper again";
+                report.set(ZK_DELETED_CONDITION, false, "ZooKeeperCoordination", msg);
+            }
+            return Ok(false);
+        }
+        if deleted {
+            return Ok(false);
+        }
+        let updated = self.delete_zookeeper(name, ns).await?;
+        let msg = "ZooKeeper deployed by Kubefi is deleted, NiFi uses Kubernetes coordination";
+        report.set(ZK_DELETED_CONDITION, true, "KubernetesCoordination", msg);
+        Ok(updated)
+    }
+
+    async fn delete_zookeeper(&self, name: &str, ns: &str) -> Result<bool> {
+        let zk_name = format!("{}-zookeeper", name);
+        let zk_headless_name = format!("{}-zookeeper-headless", name);
+        let auth_name = auth_secret_name(name);
+        let sts = delete_if_exists::<StatefulSet>(&self.client, ns, &zk_name);
+        let svc = delete_if_exists::<Service>(&self.client, ns, &zk_name);
+        let headless_svc = delete_if_exists::<Service>(&self.client, ns, &zk_headless_name);
+        let cm = delete_if_exists::<ConfigMap>(&self.client, ns, &zk_name);
+        let secret = delete_if_exists::<Secret>(&self.client, ns, &auth_name);
+        let (r1, r2, r3, r4, r5) = futures::future::join5(sts, svc, headless_svc, cm, secret).await;
+        Ok(r1? | r2? | r3? | r4? | r5?)
     }
 }
 
@@ -308,6 +720,21 @@ async fn delete_resources<T: Resource + Clone + DeserializeOwned + Meta + Debug>
         .fold(Ok(()), |acc, r| acc.and(r.map_err(Error::from)))
 }
 
+/// Deletes the resource, returns false if it does not exist
+async fn delete_if_exists<T: Resource + Clone + DeserializeOwned + Meta>(
+    client: &Client,
+    ns: &str,
+    name: &str,
+) -> Result<bool> {
+    let api = get_api::<T>(client, ns);
+    if api.get(name).await.is_err() {
+        return Ok(false);
+    }
+    debug!("Deleting {}: {}", read_type::<T>("resource"), &name);
+    api.delete(name, &DeleteParams::default()).await?;
+    Ok(true)
+}
+
 async fn find_names<T: Resource + Clone + DeserializeOwned + Meta>(
     client: &Client,
     ns: &str,
@@ -319,6 +746,51 @@ async fn find_names<T: Resource + Clone + DeserializeOwned + Meta>(
     Ok(names)
 }
 
+async fn wait_deleted<T: Resource + Clone + DeserializeOwned + Meta>(
+    api: &Api<T>,
+    name: &str,
+) -> Result<()> {
+    for _ in 0..DELETE_WAIT_ATTEMPTS {
+        if api.get(name).await.is_err() {
+            return Ok(());
+        }
+        debug!(
+            "Waiting for {} {} to be deleted",
+            read_type::<T>("resource"),
+            &name
+        );
+        delay_for(Duration::from_secs(1)).await;
+    }
+    Err(Error::msg(format!(
+        "{} {} is not deleted after {} seconds",
+        read_type::<T>("Resource"),
+        name,
+        DELETE_WAIT_ATTEMPTS
+    )))
+}
+
+/// Whether all non-null values of the expected JSON are present in the current one,
+/// so that defaults added by the API server are not detected as changes
+fn json_subset(expected: &Value, current: &Value) -> bool {
+    match (expected, current) {
+        (Value::Object(e), Value::Object(c)) => e
+            .iter()
+            .all(|(k, v)| v.is_null() || c.get(k).map(|cv| json_subset(v, cv)).unwrap_or(false)),
+        (Value::Array(e), Value::Array(c)) => {
+            e.len() == c.len() && e.iter().zip(c).all(|(ev, cv)| json_subset(ev, cv))
+        }
+        _ => expected == current,
+    }
+}
+
+/// FNV-1a, which is stable across operator versions unlike the std hasher
+fn hash(value: &str) -> String {
+    let hash = value.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
 fn get_api<T: Resource>(client: &Client, ns: &str) -> Api<T> {
     Api::namespaced(client.clone(), &ns)
 }
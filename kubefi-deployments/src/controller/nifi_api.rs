@@ -0,0 +1,364 @@
+use anyhow::{Error, Result};
+use k8s_openapi::api::core::v1::Secret;
+use reqwest::{Certificate, Client, RequestBuilder, StatusCode};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::time::Duration;
+
+use crate::controller::get_api;
+use crate::controller::tls::ca_secret_name;
+use crate::crd::{NiFiDeploymentSpec, TlsMode};
+
+const USERNAME_KEY: &str = "username";
+const PASSWORD_KEY: &str = "password";
+const CA_KEY: &str = "ca.crt";
+/// Reconciliation waits for NiFi API, so a hanging node must not block it
+const API_TIMEOUT_SECS: u64 = 10;
+
+/// Status of a node as reported by the cluster coordinator
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ClusterNode {
+    pub node_id: String,
+    pub address: String,
+    pub status: String,
+}
+
+#[derive(Deserialize)]
+struct ClusterEntity {
+    cluster: Cluster,
+}
+
+#[derive(Deserialize)]
+struct Cluster {
+    nodes: Vec<ClusterNode>,
+}
+
+/// Client of the NiFi REST API. Secure clusters are accessed with a token of the user
+/// from `spec.apiCredentialsSecret`. Their certificate is verified with `ca.crt` of that Secret,
+/// or with the CA of `tls.mode: generate`.
+pub struct NiFiApi {
+    client: Client,
+    url: String,
+    token: Option<String>,
+}
+
+impl NiFiApi {
+    pub async fn connect(
+        kube_client: &kube::Client,
+        name: &str,
+        ns: &str,
+        url: &str,
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<NiFiApi> {
+        let secret = match &spec.api_credentials_secret {
+            Some(name) => Some(get_api::<Secret>(kube_client, ns).get(name).await?),
+            None => None,
+        };
+        let data = secret.and_then(|s| s.data).unwrap_or_default();
+        let ca = match data.get(CA_KEY) {
+            Some(ca) => Some(ca.0.clone()),
+            None if url.starts_with("https") => {
+                Some(cluster_ca(kube_client, name, ns, spec).await?)
+            }
+            None => None,
+        };
+        let mut builder = Client::builder();
+        if let Some(ca) = ca {
+            builder = builder.add_root_certificate(Certificate::from_pem(&ca)?);
+        }
+        let client = builder
+            .timeout(Duration::from_secs(API_TIMEOUT_SECS))
+            .build()?;
+        let value = |key: &str| {
+            data.get(key)
+                .map(|v| String::from_utf8_lossy(&v.0).to_string())
+        };
+        let token = match (value(USERNAME_KEY), value(PASSWORD_KEY)) {
+            (Some(username), Some(password)) => {
+                let response = client
+                    .post(&format!("{}/access/token", url))
+                    .form(&[("username", username), ("password", password)])
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Some(response.text().await?)
+            }
+            _ if url.starts_with("https") => {
+                return Err(Error::msg(
+                    "apiCredentialsSecret with username and password is required for NiFi API \
+                     of a secure cluster",
+                ))
+            }
+            _ => None,
+        };
+        Ok(NiFiApi {
+            client,
+            url: url.to_string(),
+            token,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn cluster_nodes(&self) -> Result<Vec<ClusterNode>> {
+        let request = self.client.get(&format!("{}/controller/cluster", self.url));
+        let entity: ClusterEntity = self
+            .authorized(request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(entity.cluster.nodes)
+    }
+
+    /// Requests the node to be disconnected, offloaded or connected again
+    pub async fn set_node_status(&self, node_id: &str, status: &str) -> Result<()> {
+        let body = json!({ "node": { "nodeId": node_id, "status": status }});
+        let url = format!("{}/controller/cluster/nodes/{}", self.url, node_id);
+        self.authorized(self.client.put(&url))
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Removes a disconnected or offloaded node from the cluster
+    pub async fn remove_node(&self, node_id: &str) -> Result<()> {
+        let url = format!("{}/controller/cluster/nodes/{}", self.url, node_id);
+        self.authorized(self.client.delete(&url))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Id of the user with the given identity
+    pub async fn find_user(&self, identity: &str) -> Result<Option<String>> {
+        let request = self.client.get(&format!("{}/tenants/users", self.url));
+        let users: Value = self
+            .authorized(request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let id = users["users"]
+            .as_array()
+            .and_then(|users| {
+                users
+                    .iter()
+                    .find(|u| u["component"]["identity"] == identity)
+            })
+            .and_then(|u| u["id"].as_str().map(String::from));
+        Ok(id)
+    }
+
+    pub async fn create_user(&self, identity: &str) -> Result<String> {
+        let body = json!({
+            "revision": { "version": 0 },
+            "component": { "identity": identity }
+        });
+        let request = self.client.post(&format!("{}/tenants/users", self.url));
+        let user: Value = self
+            .authorized(request)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        user["id"]
+            .as_str()
+            .map(String::from)
+            .ok_or_else(|| Error::msg(format!("NiFi user {} has no id", identity)))
+    }
+
+    /// Adds users to the policy of the resource, the policy is created if it does not exist
+    pub async fn add_policy_users(
+        &self,
+        action: &str,
+        resource: &str,
+        user_ids: &[String],
+    ) -> Result<()> {
+        let url = format!("{}/policies/{}{}", self.url, action, resource);
+        let response = self.authorized(self.client.get(&url)).send().await?;
+        let policy: Option<Value> = if response.status() == StatusCode::NOT_FOUND {
+            None
+        } else {
+            Some(response.error_for_status()?.json().await?)
+        };
+        // a policy of the parent resource is returned when the resource has none
+        let policy = policy.filter(|p| p["component"]["resource"] == resource);
+
+        let request = match policy {
+            Some(policy) => {
+                let mut users = policy["component"]["users"]
+                    .as_array()
+                    .cloned()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|u| u["id"].as_str().map(String::from))
+                    .collect::<Vec<_>>();
+                let missing = user_ids
+                    .iter()
+                    .filter(|id| !users.contains(id))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if missing.is_empty() {
+                    return Ok(());
+                }
+                users.extend(missing);
+                let body = json!({
+                    "revision": policy["revision"],
+                    "component": {
+                        "id": policy["id"],
+                        "users": users.iter().map(|id| json!({ "id": id })).collect::<Vec<_>>()
+                    }
+                });
+                let id = policy["id"].as_str().unwrap_or_default();
+                let url = format!("{}/policies/{}", self.url, id);
+                self.client.put(&url).json(&body)
+            }
+            None => {
+                let body = json!({
+                    "revision": { "version": 0 },
+                    "component": {
+                        "action": action,
+                        "resource": resource,
+                        "users": user_ids.iter().map(|id| json!({ "id": id })).collect::<Vec<_>>()
+                    }
+                });
+                self.client
+                    .post(&format!("{}/policies", self.url))
+                    .json(&body)
+            }
+        };
+        self.authorized(request).send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Flowfiles and bytes queued in the cluster
+    pub async fn queued(&self) -> Result<(u64, u64)> {
+        let status = self.get_json("/flow/status").await?;
+        let controller = &status["controllerStatus"];
+        Ok((
+            controller["flowFilesQueued"].as_u64().unwrap_or(0),
+            controller["bytesQueued"].as_u64().unwrap_or(0),
+        ))
+    }
+
+    /// Connections of all process groups, which have reached their back-pressure threshold
+    pub async fn backpressured_connections(&self) -> Result<u32> {
+        let status = self
+            .get_json("/flow/process-groups/root/status?recursive=true")
+            .await?;
+        Ok(backpressured(
+            &status["processGroupStatus"]["aggregateSnapshot"],
+        ))
+    }
+
+    async fn get_json(&self, path: &str) -> Result<Value> {
+        let request = self.client.get(&format!("{}{}", self.url, path));
+        let value = self
+            .authorized(request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(value)
+    }
+
+    fn authorized(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+/// CA certificate generated by Kubefi. Other secure clusters need ca.crt in the credentials Secret,
+/// NiFi certificates are never accepted unverified.
+async fn cluster_ca(
+    kube_client: &kube::Client,
+    name: &str,
+    ns: &str,
+    spec: &NiFiDeploymentSpec,
+) -> Result<Vec<u8>> {
+    if spec.tls_mode() != Some(&TlsMode::Generate) {
+        return Err(Error::msg(format!(
+            "apiCredentialsSecret needs {} to verify the certificate of the secure NiFi cluster",
+            CA_KEY
+        )));
+    }
+    let secret_name = ca_secret_name(name);
+    get_api::<Secret>(kube_client, ns)
+        .get(&secret_name)
+        .await?
+        .data
+        .and_then(|data| data.get(CA_KEY).map(|ca| ca.0.clone()))
+        .ok_or_else(|| Error::msg(format!("Secret {} has no {} key", secret_name, CA_KEY)))
+}
+
+/// Node of the StatefulSet Pod, NiFi reports Pod FQDN as node address
+pub fn find_node<'a>(nodes: &'a [ClusterNode], pod_name: &str) -> Option<&'a ClusterNode> {
+    let prefix = format!("{}.", pod_name);
+    nodes
+        .iter()
+        .find(|n| n.address == pod_name || n.address.starts_with(&prefix))
+}
+
+fn backpressured(group: &Value) -> u32 {
+    let items = |key: &str| group[key].as_array().cloned().unwrap_or_default();
+    let connections = items("connectionStatusSnapshots")
+        .iter()
+        .map(|c| &c["connectionStatusSnapshot"])
+        .filter(|c| {
+            let percent = |key: &str| c[key].as_u64().unwrap_or(0);
+            percent("percentUseCount") >= 100 || percent("percentUseBytes") >= 100
+        })
+        .count() as u32;
+    let children = items("processGroupStatusSnapshots")
+        .iter()
+        .map(|g| backpressured(&g["processGroupStatusSnapshot"]))
+        .sum::<u32>();
+    connections + children
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_node_by_pod_name() {
+        let nodes: Vec<ClusterNode> = serde_json::from_value(json!([
+            { "nodeId": "a", "address": "nifi-1.nifi-headless.test.svc.cluster.local", "status": "CONNECTED" },
+            { "nodeId": "b", "address": "nifi-10.nifi-headless.test.svc.cluster.local", "status": "CONNECTED" }
+        ]))
+        .unwrap();
+        assert_eq!(find_node(&nodes, "nifi-1").unwrap().node_id, "a");
+        assert_eq!(find_node(&nodes, "nifi-10").unwrap().node_id, "b");
+        assert!(find_node(&nodes, "nifi-2").is_none());
+    }
+
+    #[test]
+    fn count_backpressured_connections() {
+        let connection = |count: u64, bytes: u64| {
+            json!({ "connectionStatusSnapshot": {
+                "percentUseCount": count, "percentUseBytes": bytes
+            }})
+        };
+        let root = json!({
+            "connectionStatusSnapshots": [connection(100, 10), connection(20, 30)],
+            "processGroupStatusSnapshots": [{ "processGroupStatusSnapshot": {
+                "connectionStatusSnapshots": [connection(0, 100)]
+            }}]
+        });
+        assert_eq!(backpressured(&root), 2);
+    }
+}
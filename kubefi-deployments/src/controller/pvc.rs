@@ -0,0 +1,337 @@
+use anyhow::{Error, Result};
+use k8s_openapi::api::apps::v1::StatefulSet;
+use k8s_openapi::api::core::v1::PersistentVolumeClaim;
+use k8s_openapi::api::storage::v1::StorageClass;
+use kube::api::PatchParams;
+use kube::{Api, Client};
+
+use crate::controller::{get_api, StatusReport};
+
+pub const VOLUME_EXPANSION_CONDITION: &str = "VolumeExpansion";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct VolumeClaim {
+    pub name: Option<String>,
+    pub storage_class: Option<String>,
+    pub access_modes: Option<Vec<String>>,
+    pub size: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ClaimsChange {
+    Unchanged,
+    /// Only sizes of the listed volume claims are increased
+    Expanded(Vec<VolumeClaim>),
+    /// Same volumes with another storage class or access modes, data has to be migrated
+    StorageChanged,
+    Changed,
+}
+
+pub fn claims_change(current: &StatefulSet, expected: &StatefulSet) -> Result<ClaimsChange> {
+    let current_claims = volume_claims(current);
+    let expected_claims = volume_claims(expected);
+    if current_claims == expected_claims {
+        return Ok(ClaimsChange::Unchanged);
+    }
+    debug!(
+        "Volume claims changed, current: {:?}, expected: {:?}",
+        &current_claims, &expected_claims
+    );
+    let same_names = current_claims.len() == expected_claims.len()
+        && current_claims
+            .iter()
+            .zip(expected_claims.iter())
+            .all(|(c, e)| c.name == e.name);
+    if !same_names {
+        return Ok(ClaimsChange::Changed);
+    }
+    let same_storage = current_claims
+        .iter()
+        .zip(expected_claims.iter())
+        .all(|(c, e)| c.storage_class == e.storage_class && c.access_modes == e.access_modes);
+    if !same_storage {
+        return Ok(ClaimsChange::StorageChanged);
+    }
+
+    let mut expanded = Vec::new();
+    for (c, e) in current_claims.iter().zip(expected_claims) {
+        match (size_bytes(&c.size), size_bytes(&e.size)) {
+            (Some(current_size), Some(expected_size)) if expected_size < current_size => {
+                return Err(Error::msg(format!(
+                    "Volume {:?} cannot be shrunk from {:?} to {:?}",
+                    c.name, c.size, e.size
+                )))
+            }
+            (Some(current_size), Some(expected_size)) if expected_size > current_size => {
+                expanded.push(e)
+            }
+            (Some(_), Some(_)) => (),
+            _ => return Ok(ClaimsChange::Changed),
+        }
+    }
+    if expanded.is_empty() {
+        // same sizes written in another notation, e.g. 1024Mi and 1Gi
+        return Ok(ClaimsChange::Unchanged);
+    }
+    Ok(ClaimsChange::Expanded(expanded))
+}
+
+pub fn volume_claims(set: &StatefulSet) -> Vec<VolumeClaim> {
+    set.spec
+        .as_ref()
+        .and_then(|s| s.volume_claim_templates.clone())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|pvc| {
+            let spec = pvc.spec.unwrap_or_default();
+            let size = spec
+                .resources
+                .and_then(|r| r.requests)
+                .and_then(|r| r.get("storage").map(|q| q.0.clone()));
+            VolumeClaim {
+                name: pvc.metadata.name,
+                storage_class: spec.storage_class_name,
+                access_modes: spec.access_modes,
+                size,
+            }
+        })
+        .collect()
+}
+
+/// Requests new size for existing PVCs of every StatefulSet replica
+pub async fn expand_claims(
+    client: &Client,
+    ns: &str,
+    set_name: &str,
+    replicas: i32,
+    claims: &[VolumeClaim],
+    report: &StatusReport,
+) -> Result<()> {
+    let storage_classes: Api<StorageClass> = Api::all(client.clone());
+    for sc in claims.iter().filter_map(|c| c.storage_class.as_ref()) {
+        let expandable = storage_classes
+            .get(sc)
+            .await?
+            .allow_volume_expansion
+            .unwrap_or(false);
+        if !expandable {
+            let msg = format!("StorageClass {} does not allow volume expansion", sc);
+            report.set(VOLUME_EXPANSION_CONDITION, false, "NotSupported", &msg);
+            return Err(Error::msg(msg));
+        }
+    }
+
+    let api = get_api::<PersistentVolumeClaim>(client, ns);
+    for claim in claims {
+        let (name, size) = match (&claim.name, &claim.size) {
+            (Some(name), Some(size)) => (name, size),
+            _ => continue,
+        };
+        for pvc_name in pvc_names(name, set_name, replicas) {
+            let patch = json!({ "spec": { "resources": { "requests": { "storage": size }}}});
+            debug!("Expanding PVC {} to {}", &pvc_name, size);
+            api.patch(
+                &pvc_name,
+                &PatchParams::default(),
+                serde_json::to_vec(&patch)?,
+            )
+            .await?;
+        }
+    }
+    let msg = format!(
+        "Requested new size for {} of {}",
+        volume_names(claims),
+        set_name
+    );
+    report.set(VOLUME_EXPANSION_CONDITION, true, "Resizing", &msg);
+    Ok(())
+}
+
+/// Reports the number of resized PVCs while the expansion is in progress
+pub async fn expansion_progress(
+    client: &Client,
+    ns: &str,
+    set: &StatefulSet,
+    report: &StatusReport,
+) -> Result<()> {
+    let in_progress = report
+        .previous(VOLUME_EXPANSION_CONDITION)
+        .map(|c| c.status == "True")
+        .unwrap_or(false);
+    let set_name = match (&set.metadata.name, in_progress) {
+        (Some(name), true) => name,
+        _ => return Ok(()),
+    };
+    let replicas = set.spec.as_ref().and_then(|s| s.replicas).unwrap_or(0);
+    let api = get_api::<PersistentVolumeClaim>(client, ns);
+    let (mut resized, mut total) = (0, 0);
+    for claim in volume_claims(set) {
+        let name = match &claim.name {
+            Some(name) => name,
+            None => continue,
+        };
+        for pvc_name in pvc_names(name, set_name, replicas) {
+            let pvc = api.get(&pvc_name).await?;
+            total += 1;
+            if pvc_resized(&pvc) {
+                resized += 1;
+            }
+        }
+    }
+    let msg = format!(
+        "{} of {} volumes of {} are resized",
+        resized, total, set_name
+    );
+    if resized < total {
+        report.set(VOLUME_EXPANSION_CONDITION, true, "Resizing", &msg);
+    } else {
+        report.set(VOLUME_EXPANSION_CONDITION, false, "Completed", &msg);
+    }
+    Ok(())
+}
+
+fn pvc_resized(pvc: &PersistentVolumeClaim) -> bool {
+    let requested = pvc
+        .spec
+        .as_ref()
+        .and_then(|s| s.resources.as_ref())
+        .and_then(|r| r.requests.as_ref())
+        .and_then(|r| r.get("storage"))
+        .map(|q| q.0.clone());
+    let capacity = pvc
+        .status
+        .as_ref()
+        .and_then(|s| s.capacity.as_ref())
+        .and_then(|c| c.get("storage"))
+        .map(|q| q.0.clone());
+    match (size_bytes(&requested), size_bytes(&capacity)) {
+        (Some(requested), Some(capacity)) => capacity >= requested,
+        _ => false,
+    }
+}
+
+pub fn pvc_names(claim: &str, set_name: &str, replicas: i32) -> Vec<String> {
+    (0..replicas)
+        .map(|i| format!("{}-{}-{}", claim, set_name, i))
+        .collect()
+}
+
+fn volume_names(claims: &[VolumeClaim]) -> String {
+    claims
+        .iter()
+        .filter_map(|c| c.name.clone())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Converts Kubernetes quantity like 512Mi or 5G to bytes
+pub fn size_bytes(quantity: &Option<String>) -> Option<f64> {
+    let q = quantity.as_ref()?.trim();
+    let suffixes = [
+        ("Ki", 1024f64),
+        ("Mi", 1024f64.powi(2)),
+        ("Gi", 1024f64.powi(3)),
+        ("Ti", 1024f64.powi(4)),
+        ("Pi", 1024f64.powi(5)),
+        ("Ei", 1024f64.powi(6)),
+        ("k", 1e3),
+        ("M", 1e6),
+        ("G", 1e9),
+        ("T", 1e12),
+        ("P", 1e15),
+        ("E", 1e18),
+        ("m", 1e-3),
+    ];
+    let (number, multiplier) = suffixes
+        .iter()
+        .find(|(suffix, _)| q.ends_with(suffix))
+        .map(|(suffix, m)| (&q[..q.len() - suffix.len()], *m))
+        .unwrap_or((q, 1f64));
+    number.parse::<f64>().ok().map(|n| n * multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(sizes: &[(&str, &str)]) -> StatefulSet {
+        set_with_class(sizes, "standard")
+    }
+
+    fn set_with_class(sizes: &[(&str, &str)], storage_class: &str) -> StatefulSet {
+        let claims = sizes
+            .iter()
+            .map(|(name, size)| {
+                json!({
+                    "metadata": { "name": name },
+                    "spec": {
+                        "accessModes": ["ReadWriteOnce"],
+                        "storageClassName": storage_class,
+                        "resources": { "requests": { "storage": size }}
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::from_value(json!({
+            "metadata": { "name": "test" },
+            "spec": {
+                "selector": {},
+                "serviceName": "test",
+                "template": {},
+                "volumeClaimTemplates": claims
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn detect_claims_change() {
+        let current = set(&[("data", "512Mi"), ("logs", "1Gi")]);
+        assert_eq!(
+            claims_change(&current, &current).unwrap(),
+            ClaimsChange::Unchanged
+        );
+
+        let expanded = set(&[("data", "1Gi"), ("logs", "1024Mi")]);
+        match claims_change(&current, &expanded).unwrap() {
+            ClaimsChange::Expanded(claims) => {
+                assert_eq!(claims.len(), 1);
+                assert_eq!(claims[0].name, Some("data".to_string()));
+            }
+            other => panic!("Unexpected change: {:?}", other),
+        }
+
+        assert!(claims_change(&current, &set(&[("data", "256Mi"), ("logs", "1Gi")])).is_err());
+        assert_eq!(
+            claims_change(&current, &set(&[("data", "512Mi")])).unwrap(),
+            ClaimsChange::Changed
+        );
+        assert_eq!(
+            claims_change(
+                &current,
+                &set_with_class(&[("data", "512Mi"), ("logs", "1Gi")], "fast")
+            )
+            .unwrap(),
+            ClaimsChange::StorageChanged
+        );
+    }
+
+    #[test]
+    fn same_size_in_another_notation() {
+        let current = set(&[("data", "1024Mi"), ("logs", "1Gi")]);
+        let expected = set(&[("data", "1Gi"), ("logs", "1073741824")]);
+        assert_eq!(
+            claims_change(&current, &expected).unwrap(),
+            ClaimsChange::Unchanged
+        );
+    }
+
+    #[test]
+    fn parse_size() {
+        assert_eq!(size_bytes(&Some("512Mi".to_string())), Some(536_870_912f64));
+        assert_eq!(size_bytes(&Some("5G".to_string())), Some(5e9));
+        assert_eq!(size_bytes(&Some("1024".to_string())), Some(1024f64));
+        assert!(size_bytes(&Some("2Gi".to_string())) > size_bytes(&Some("2000Mi".to_string())));
+        assert_eq!(size_bytes(&Some("abc".to_string())), None);
+    }
+}
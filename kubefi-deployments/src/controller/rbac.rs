@@ -0,0 +1,98 @@
+use std::rc::Rc;
+
+use anyhow::Result;
+use k8s_openapi::api::core::v1::ServiceAccount;
+use k8s_openapi::api::rbac::v1::{Role, RoleBinding};
+use kube::api::PostParams;
+use kube::Client;
+
+use crate::controller::{delete_if_exists, from_yaml, get_api, get_or_create};
+use crate::crd::NiFiDeploymentSpec;
+use crate::template::Template;
+
+use super::either::Either;
+use super::either::Either::{Left, Right};
+
+/// ServiceAccount, Role and RoleBinding which allow NiFi Pods to manage Leases and ConfigMaps
+/// when the cluster uses Kubernetes coordination, and to read the Secret of their own certificate
+/// when the operator provides TLS
+pub struct RbacController {
+    pub client: Rc<Client>,
+    pub template: Rc<Template>,
+}
+
+impl RbacController {
+    pub async fn handle_rbac(
+        &self,
+        name: &str,
+        ns: &str,
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<bool> {
+        let sa = get_or_create::<ServiceAccount, _>(&self.client, name, name, ns, |name| {
+            self.template.nifi_service_account(name, spec)
+        });
+        let role = get_or_create::<Role, _>(&self.client, name, name, ns, |name| {
+            self.template.nifi_role(name, spec)
+        });
+        let binding = get_or_create::<RoleBinding, _>(&self.client, name, name, ns, |name| {
+            self.template.nifi_role_binding(name, spec)
+        });
+        let (sa, role, binding) = futures::future::join3(sa, role, binding).await;
+        let (sa, role, binding) = (sa?, role?, binding?);
+
+        if nifi_rbac(spec) {
+            let any_created = created(&sa) || created(&role) || created(&binding);
+            let role_updated = match role {
+                Left(Some(current)) => self.update_role(name, ns, current, spec).await?,
+                _ => false,
+            };
+            return Ok(any_created || role_updated);
+        }
+        // coordination has been switched back to ZooKeeper and TLS is disabled
+        let mut deleted = false;
+        if let Left(Some(_)) = binding {
+            deleted |= delete_if_exists::<RoleBinding>(&self.client, ns, name).await?;
+        }
+        if let Left(Some(_)) = role {
+            deleted |= delete_if_exists::<Role>(&self.client, ns, name).await?;
+        }
+        if let Left(Some(_)) = sa {
+            deleted |= delete_if_exists::<ServiceAccount>(&self.client, ns, name).await?;
+        }
+        Ok(deleted)
+    }
+
+    /// Secrets of NiFi nodes change with the number of replicas and TLS mode
+    async fn update_role(
+        &self,
+        name: &str,
+        ns: &str,
+        current: Role,
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<bool> {
+        let expected = match self.template.nifi_role(name, spec)? {
+            Some(yaml) => from_yaml::<Role>(&yaml)?,
+            None => return Ok(false),
+        };
+        if current.rules == expected.rules {
+            return Ok(false);
+        }
+        debug!("Updating Role: {}", name);
+        let updated = Role {
+            rules: expected.rules,
+            ..current
+        };
+        get_api::<Role>(&self.client, ns)
+            .replace(name, &PostParams::default(), &updated)
+            .await?;
+        Ok(true)
+    }
+}
+
+fn nifi_rbac(spec: &NiFiDeploymentSpec) -> bool {
+    spec.kubernetes_coordination() || spec.tls.is_some()
+}
+
+fn created<T>(res: &Either<Option<T>, Option<T>>) -> bool {
+    matches!(res, Right(Some(_)))
+}
@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use anyhow::{Error, Result};
+use k8s_openapi::api::apps::v1::ControllerRevision;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
+use k8s_openapi::apimachinery::pkg::runtime::RawExtension;
+use k8s_openapi::Resource;
+use kube::api::{DeleteParams, ListParams, Meta, ObjectMeta, PostParams};
+use kube::Client;
+
+use crate::controller::{get_api, hash, StatusReport};
+use crate::crd::{NiFiDeployment, NiFiDeploymentSpec};
+use crate::template::Template;
+
+pub const ROLLBACK_CONDITION: &str = "Rollback";
+/// Set to a revision number to restore the spec applied with that revision
+pub const ROLLBACK_ANNOTATION: &str = "kubefi.io/rollback-to";
+
+const DEFAULT_REVISION_HISTORY_LIMIT: u8 = 10;
+const REVISION_OF_LABEL: &str = "kubefi.io/nifideployment";
+
+/// Revision numbers reported in the NiFiDeployment status
+#[derive(Debug, Default, PartialEq)]
+pub struct Revisions {
+    pub current: Option<i64>,
+    pub previous: Option<i64>,
+}
+
+/// Keeps applied specs of a NiFiDeployment as ControllerRevisions owned by it.
+///
+/// A revision holds the spec and the hash of the manifests rendered from it. Applying a spec
+/// of an older revision again, for example by the rollback annotation, renumbers that revision
+/// as the latest one instead of creating a duplicate, like Deployments do.
+pub struct RevisionController {
+    pub client: Rc<Client>,
+    pub template: Rc<Template>,
+}
+
+impl RevisionController {
+    /// Restores the spec of the revision set by the rollback annotation and removes the annotation.
+    /// Returns the updated NiFiDeployment, which is applied instead of the received one.
+    pub async fn rollback(
+        &self,
+        d: &NiFiDeployment,
+        name: &str,
+        ns: &str,
+        report: &StatusReport,
+    ) -> Result<Option<NiFiDeployment>> {
+        let target = match d
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|a| a.get(ROLLBACK_ANNOTATION))
+        {
+            Some(target) => target.clone(),
+            None => return Ok(None),
+        };
+        let revisions = self.list(name, ns).await?;
+        let spec = target
+            .trim()
+            .parse::<i64>()
+            .ok()
+            .and_then(|number| revisions.iter().find(|r| r.revision == number))
+            .and_then(spec_of);
+
+        let mut updated = d.clone();
+        if let Some(annotations) = updated.metadata.annotations.as_mut() {
+            annotations.remove(ROLLBACK_ANNOTATION);
+        }
+        match spec {
+            Some(spec) => {
+                updated.spec = spec;
+                let msg = format!("Spec is restored from revision {}", target);
+                report.set(ROLLBACK_CONDITION, true, "RolledBack", &msg);
+            }
+            None => {
+                let available = revisions
+                    .iter()
+                    .map(|r| r.revision.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                let msg = format!(
+                    "Revision {} is not found, available revisions: {}",
+                    target, available
+                );
+                report.set(ROLLBACK_CONDITION, false, "RevisionNotFound", &msg);
+            }
+        }
+        debug!(
+            "Rolling back NiFiDeployment {} to revision {}",
+            name, target
+        );
+        let updated = get_api::<NiFiDeployment>(&self.client, ns)
+            .replace(name, &PostParams::default(), &updated)
+            .await?;
+        Ok(Some(updated))
+    }
+
+    /// Records the applied spec as the latest revision and deletes revisions above the history limit
+    pub async fn record(
+        &self,
+        d: &NiFiDeployment,
+        spec: &NiFiDeploymentSpec,
+        name: &str,
+        ns: &str,
+    ) -> Result<Revisions> {
+        let data = json!({
+            "spec": spec,
+            "manifestsHash": self.manifests_hash(name, ns, spec)?
+        });
+        let revision_name = format!("{}-{}", name, &hash(&data.to_string())[..10]);
+        let api = get_api::<ControllerRevision>(&self.client, ns);
+        let mut revisions = self.list(name, ns).await?;
+        let latest = revisions.last().map(|r| r.revision).unwrap_or(0);
+
+        let existing = revisions
+            .iter()
+            .position(|r| r.metadata.name.as_deref() == Some(&revision_name));
+        match existing {
+            Some(i) if revisions[i].revision == latest => (),
+            Some(i) => {
+                let mut revision = revisions.remove(i);
+                debug!(
+                    "Renumbering revision {} of {} to {}",
+                    revision.revision,
+                    name,
+                    latest + 1
+                );
+                revision.revision = latest + 1;
+                let revision = api
+                    .replace(&revision_name, &PostParams::default(), &revision)
+                    .await?;
+                revisions.push(revision);
+            }
+            None => {
+                debug!("Creating revision {} of {}", latest + 1, name);
+                let revision = ControllerRevision {
+                    metadata: ObjectMeta {
+                        name: Some(revision_name),
+                        labels: Some(labels(name)),
+                        owner_references: Some(vec![owner_reference(d, name)?]),
+                        ..ObjectMeta::default()
+                    },
+                    data: Some(RawExtension(data)),
+                    revision: latest + 1,
+                };
+                revisions.push(api.create(&PostParams::default(), &revision).await?);
+            }
+        }
+
+        let limit = spec
+            .revision_history_limit
+            .unwrap_or(DEFAULT_REVISION_HISTORY_LIMIT)
+            .max(1) as usize;
+        let excess = revisions.len().saturating_sub(limit);
+        for old in revisions.drain(..excess) {
+            debug!("Deleting revision {} of {}", old.revision, name);
+            api.delete(&Meta::name(&old), &DeleteParams::default())
+                .await?;
+        }
+        let mut numbers = revisions.iter().rev().map(|r| r.revision);
+        Ok(Revisions {
+            current: numbers.next(),
+            previous: numbers.next(),
+        })
+    }
+
+    /// Revisions of the NiFiDeployment, the oldest first
+    async fn list(&self, name: &str, ns: &str) -> Result<Vec<ControllerRevision>> {
+        let lp = ListParams::default().labels(&format!("{}={}", REVISION_OF_LABEL, name));
+        let mut revisions = get_api::<ControllerRevision>(&self.client, ns)
+            .list(&lp)
+            .await?
+            .items;
+        revisions.sort_by_key(|r| r.revision);
+        Ok(revisions)
+    }
+
+    fn manifests_hash(&self, name: &str, ns: &str, spec: &NiFiDeploymentSpec) -> Result<String> {
+        let manifests = vec![
+            self.template.nifi_configmap(name, ns, spec)?,
+            self.template.nifi_statefulset(name, spec)?,
+            self.template.zk_configmap(name, spec)?,
+            self.template.zk_statefulset(name, spec)?,
+        ];
+        let manifests = manifests
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join("\n---\n");
+        Ok(hash(&manifests))
+    }
+}
+
+fn spec_of(revision: &ControllerRevision) -> Option<NiFiDeploymentSpec> {
+    let spec = revision.data.as_ref().map(|d| d.0["spec"].clone())?;
+    serde_json::from_value::<NiFiDeploymentSpec>(spec).ok()
+}
+
+fn labels(name: &str) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+    labels.insert(
+        "app.kubernetes.io/managed-by".to_string(),
+        "Kubefi".to_string(),
+    );
+    labels.insert("release".to_string(), "nifi".to_string());
+    labels.insert(REVISION_OF_LABEL.to_string(), name.to_string());
+    labels
+}
+
+/// Revisions are garbage collected together with the NiFiDeployment
+fn owner_reference(d: &NiFiDeployment, name: &str) -> Result<OwnerReference> {
+    let uid = d
+        .metadata
+        .uid
+        .clone()
+        .ok_or_else(|| Error::msg(format!("NiFiDeployment {} has no uid", name)))?;
+    Ok(OwnerReference {
+        api_version: NiFiDeployment::API_VERSION.to_string(),
+        kind: NiFiDeployment::KIND.to_string(),
+        name: name.to_string(),
+        uid,
+        controller: Some(true),
+        block_owner_deletion: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn spec_of_revision() {
+        let revision: ControllerRevision = serde_json::from_value(json!({
+            "metadata": { "name": "test-0123456789" },
+            "data": {
+                "spec": { "nifiReplicas": 3, "image": "apache/nifi:1.12.1" },
+                "manifestsHash": "cbf29ce484222325"
+            },
+            "revision": 2
+        }))
+        .unwrap();
+        let spec = spec_of(&revision).unwrap();
+        assert_eq!(spec.nifi_replicas, 3);
+        assert_eq!(spec.image, Some("apache/nifi:1.12.1".to_string()));
+
+        let empty = ControllerRevision {
+            data: Some(RawExtension(Value::Null)),
+            ..revision
+        };
+        assert!(spec_of(&empty).is_none());
+    }
+}
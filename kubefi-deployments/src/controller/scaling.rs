@@ -0,0 +1,212 @@
+use std::ops::Range;
+
+use anyhow::Result;
+use k8s_openapi::api::apps::v1::StatefulSet;
+use kube::Client;
+
+use crate::controller::nifi_api::{find_node, NiFiApi};
+use crate::controller::StatusReport;
+use crate::crd::NiFiDeploymentSpec;
+use crate::template::Template;
+
+pub const SCALE_DOWN_CONDITION: &str = "NiFiScaleDown";
+pub const NODE_IDENTITIES_CONDITION: &str = "NodeIdentities";
+
+/// Policies which every cluster node needs: proxying user requests and access to the controller
+const NODE_POLICIES: [(&str, &str); 2] = [("write", "/proxy"), ("read", "/controller")];
+
+/// Changes the number of NiFi nodes through NiFi REST API.
+///
+/// Nodes are removed one at a time, the highest ordinal first. A node is disconnected,
+/// its queues are offloaded to the remaining nodes and it is deleted from the cluster
+/// before its Pod is stopped. Every step is derived from the node status reported by NiFi,
+/// so scaling down continues after operator restarts.
+///
+/// New nodes of a secure cluster are registered as users with the node policies
+/// before their Pods are started, because initial node identities of authorizers.xml
+/// are only used when NiFi has no users yet.
+pub struct NiFiScaling<'a> {
+    pub client: &'a Client,
+    pub template: &'a Template,
+    pub name: &'a str,
+    pub ns: &'a str,
+}
+
+impl<'a> NiFiScaling<'a> {
+    /// Returns the number of replicas the NiFi StatefulSet can have now
+    pub async fn replicas(
+        &self,
+        current: &StatefulSet,
+        spec: &NiFiDeploymentSpec,
+        report: &StatusReport,
+    ) -> Result<i32> {
+        let target = spec.nifi_replicas as i32;
+        let replicas = current.spec.as_ref().and_then(|s| s.replicas).unwrap_or(0);
+        if target > replicas {
+            return Ok(self.scale_up(replicas, target, spec, report).await);
+        }
+        // without remaining nodes there is nowhere to offload the data to
+        if target == replicas || target == 0 {
+            let scaling = report
+                .previous(SCALE_DOWN_CONDITION)
+                .map(|c| c.status == "True")
+                .unwrap_or(false);
+            if scaling && target == replicas {
+                let msg = format!("NiFi cluster has {} nodes", target);
+                report.set(SCALE_DOWN_CONDITION, false, "Completed", &msg);
+            }
+            return Ok(target);
+        }
+
+        let pod_name = format!("{}-{}", self.name, replicas - 1);
+        match self.remove_node(&pod_name, spec).await {
+            Ok(None) => {
+                let msg = format!(
+                    "Node {} is removed from the cluster, scaling from {} to {} nodes",
+                    pod_name,
+                    replicas,
+                    replicas - 1
+                );
+                report.set(SCALE_DOWN_CONDITION, true, "Scaling", &msg);
+                Ok(replicas - 1)
+            }
+            Ok(Some(step)) => {
+                let msg = format!(
+                    "Node {} is {} before scaling down to {} nodes",
+                    pod_name,
+                    step.to_lowercase(),
+                    target
+                );
+                report.set(SCALE_DOWN_CONDITION, true, step, &msg);
+                Ok(replicas)
+            }
+            Err(e) => {
+                let msg = format!("Failed to remove node {}: {}", pod_name, e);
+                report.set(SCALE_DOWN_CONDITION, true, "ApiError", &msg);
+                Ok(replicas)
+            }
+        }
+    }
+
+    /// Moves the node to its next status. Returns the current step as condition reason,
+    /// or None once the node is no longer in the cluster.
+    async fn remove_node(
+        &self,
+        pod_name: &str,
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<Option<&'static str>> {
+        let api = self.api(spec).await?;
+        let nodes = api.cluster_nodes().await?;
+        let node = match find_node(&nodes, pod_name) {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        debug!("Node {} has status {}", pod_name, node.status);
+        let step = match node.status.as_str() {
+            "CONNECTED" | "CONNECTING" => {
+                api.set_node_status(&node.node_id, "DISCONNECTING").await?;
+                "Disconnecting"
+            }
+            "DISCONNECTING" => "Disconnecting",
+            "DISCONNECTED" => {
+                api.set_node_status(&node.node_id, "OFFLOADING").await?;
+                "Offloading"
+            }
+            "OFFLOADING" => "Offloading",
+            "OFFLOADED" => {
+                api.remove_node(&node.node_id).await?;
+                return Ok(None);
+            }
+            _ => "Waiting",
+        };
+        Ok(Some(step))
+    }
+
+    /// Scales up once identities of the new nodes are registered. Without running nodes
+    /// there is no API to register them with, so the initial identities are used.
+    async fn scale_up(
+        &self,
+        replicas: i32,
+        target: i32,
+        spec: &NiFiDeploymentSpec,
+        report: &StatusReport,
+    ) -> i32 {
+        if replicas == 0 || !self.template.nifi_secure(self.name, spec) {
+            return target;
+        }
+        match self.register_nodes(replicas..target, spec).await {
+            Ok(()) => {
+                let msg = format!(
+                    "Nodes {} to {} are authorized to join the cluster",
+                    replicas,
+                    target - 1
+                );
+                report.set(NODE_IDENTITIES_CONDITION, true, "Registered", &msg);
+                target
+            }
+            Err(e) => {
+                let msg = format!("Failed to register identities of new nodes: {}", e);
+                report.set(NODE_IDENTITIES_CONDITION, false, "ApiError", &msg);
+                replicas
+            }
+        }
+    }
+
+    async fn register_nodes(&self, ordinals: Range<i32>, spec: &NiFiDeploymentSpec) -> Result<()> {
+        let api = self.api(spec).await?;
+        let mut user_ids = Vec::new();
+        for ordinal in ordinals {
+            let identity = node_identity(self.name, self.ns, ordinal);
+            let user_id = match api.find_user(&identity).await? {
+                Some(id) => id,
+                None => {
+                    debug!("Creating NiFi user for node {}", &identity);
+                    api.create_user(&identity).await?
+                }
+            };
+            user_ids.push(user_id);
+        }
+        for (action, resource) in NODE_POLICIES.iter() {
+            api.add_policy_users(action, resource, &user_ids).await?;
+        }
+        Ok(())
+    }
+
+    async fn api(&self, spec: &NiFiDeploymentSpec) -> Result<NiFiApi> {
+        let url = self.template.nifi_api_url(self.name, self.ns, spec);
+        NiFiApi::connect(self.client, self.name, self.ns, &url, spec).await
+    }
+}
+
+/// Same identity as the node identities of authorizers.xml
+pub fn node_identity(name: &str, ns: &str, ordinal: i32) -> String {
+    format!(
+        "{}-{}.{}-headless.{}.svc.cluster.local",
+        name, ordinal, name, ns
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::config::read_nifi_config;
+
+    #[test]
+    fn node_identity_matches_authorizers() {
+        let config = read_nifi_config().expect("Failed to load config");
+        let template = Template::new(Path::new("./templates"), config)
+            .expect("Failed to create template engine");
+        let spec = NiFiDeploymentSpec {
+            nifi_replicas: 2,
+            ..NiFiDeploymentSpec::default()
+        };
+        let cm = template
+            .nifi_configmap("test", "ns", &spec)
+            .unwrap()
+            .unwrap();
+        let identity = format!(">{}<", node_identity("test", "ns", 1));
+        assert!(cm.contains(&format!("Node Identity 1\"{}", identity)));
+    }
+}
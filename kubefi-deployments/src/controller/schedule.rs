@@ -0,0 +1,154 @@
+use std::str::FromStr;
+
+use anyhow::{Error, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use cron::Schedule;
+
+use crate::controller::StatusReport;
+use crate::crd::{NiFiDeployment, ScheduledProfile};
+
+pub const SCHEDULE_CONDITION: &str = "Schedule";
+/// Set to "true" to use spec.nifiReplicas instead of the scheduled profiles
+pub const SCHEDULE_SUSPENDED_ANNOTATION: &str = "kubefi.io/schedule-suspended";
+
+/// Applies replicas of the active scheduled profile to the NiFiDeployment spec, so that they go
+/// through the same scaling as nifiReplicas. Returns the name of the active profile.
+pub fn apply_schedule(
+    d: &mut NiFiDeployment,
+    report: &StatusReport,
+    now: DateTime<Utc>,
+) -> Option<String> {
+    let profiles = match d.spec.schedule.as_ref().filter(|p| !p.is_empty()) {
+        Some(profiles) => profiles,
+        None => {
+            if report.previous(SCHEDULE_CONDITION).is_some() {
+                let msg = "No scheduled profiles, spec.nifiReplicas is used";
+                report.set(SCHEDULE_CONDITION, false, "NotScheduled", msg);
+            }
+            return None;
+        }
+    };
+    let suspended = d
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(SCHEDULE_SUSPENDED_ANNOTATION))
+        .map(|v| v == "true")
+        .unwrap_or(false);
+    if suspended {
+        let msg = format!(
+            "Schedule is suspended by {} annotation, spec.nifiReplicas is used",
+            SCHEDULE_SUSPENDED_ANNOTATION
+        );
+        report.set(SCHEDULE_CONDITION, false, "Suspended", &msg);
+        return None;
+    }
+
+    match active_profile(profiles, now) {
+        Ok(Some((profile, since))) => {
+            let msg = format!(
+                "Profile {} with {} NiFi replicas is active since {}",
+                profile.name,
+                profile.nifi_replicas,
+                since.to_rfc3339_opts(SecondsFormat::Secs, true)
+            );
+            report.set(SCHEDULE_CONDITION, true, "Active", &msg);
+            let name = profile.name.clone();
+            let (nifi_replicas, zk_replicas) = (profile.nifi_replicas, profile.zk_replicas);
+            d.spec.nifi_replicas = nifi_replicas;
+            if let Some(replicas) = zk_replicas {
+                d.spec.zk.replicas = replicas;
+            }
+            Some(name)
+        }
+        Ok(None) => {
+            let msg = "None of the profiles has fired yet, spec.nifiReplicas is used";
+            report.set(SCHEDULE_CONDITION, false, "NotScheduled", msg);
+            None
+        }
+        Err(e) => {
+            report.set(SCHEDULE_CONDITION, false, "InvalidSchedule", &e.to_string());
+            None
+        }
+    }
+}
+
+/// Profile which cron expression fired last, with the time it fired
+fn active_profile(
+    profiles: &[ScheduledProfile],
+    now: DateTime<Utc>,
+) -> Result<Option<(&ScheduledProfile, DateTime<Utc>)>> {
+    let mut active: Option<(&ScheduledProfile, DateTime<Utc>)> = None;
+    for profile in profiles {
+        let schedule = parse_cron(&profile.cron).map_err(|e| {
+            Error::msg(format!(
+                "Invalid cron expression '{}' of profile {}: {}",
+                profile.cron, profile.name, e
+            ))
+        })?;
+        let fired = schedule.after(&now).next_back();
+        if let Some(fired) = fired {
+            if active.map(|(_, since)| fired > since).unwrap_or(true) {
+                active = Some((profile, fired));
+            }
+        }
+    }
+    Ok(active)
+}
+
+/// Accepts the standard five fields as well, which fire at the beginning of the minute
+fn parse_cron(cron: &str) -> Result<Schedule> {
+    let expression = if cron.split_whitespace().count() == 5 {
+        format!("0 {}", cron)
+    } else {
+        cron.to_string()
+    };
+    Schedule::from_str(&expression).map_err(|e| Error::msg(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn profile(name: &str, cron: &str, nifi_replicas: u8) -> ScheduledProfile {
+        ScheduledProfile {
+            name: name.to_string(),
+            cron: cron.to_string(),
+            nifi_replicas,
+            zk_replicas: None,
+        }
+    }
+
+    #[test]
+    fn last_fired_profile_is_active() {
+        let profiles = vec![
+            profile("business-hours", "0 8 * * Mon-Fri", 5),
+            profile("night", "0 20 * * *", 1),
+        ];
+        // Wednesday
+        let day = Utc.ymd(2020, 11, 4);
+        let active = |hour| {
+            active_profile(&profiles, day.and_hms(hour, 30, 0))
+                .unwrap()
+                .map(|(p, _)| p.name.clone())
+        };
+        assert_eq!(active(9), Some("business-hours".to_string()));
+        assert_eq!(active(21), Some("night".to_string()));
+        assert_eq!(active(7), Some("night".to_string()));
+
+        // Sunday
+        let weekend = Utc.ymd(2020, 11, 8).and_hms(12, 0, 0);
+        let (p, since) = active_profile(&profiles, weekend).unwrap().unwrap();
+        assert_eq!(p.name, "night");
+        assert_eq!(since, Utc.ymd(2020, 11, 7).and_hms(20, 0, 0));
+    }
+
+    #[test]
+    fn invalid_cron_is_reported() {
+        let profiles = vec![profile("broken", "every day", 1)];
+        let now = Utc.ymd(2020, 11, 4).and_hms(0, 0, 0);
+        assert!(active_profile(&profiles, now).is_err());
+    }
+}
@@ -5,8 +5,9 @@ use k8s_openapi::api::core::v1::Service;
 use kube::api::DeleteParams;
 use kube::Client;
 
+use crate::controller::cert_manager::ingress_tls_secret;
 use crate::controller::{create_from_yaml, get_api, get_or_create};
-use crate::crd::IngressCfg;
+use crate::crd::{IngressCfg, NiFiDeploymentSpec};
 use crate::template::Template;
 
 use super::either::Either;
@@ -23,40 +24,52 @@ impl ServiceController {
         &self,
         name: &str,
         ns: &str,
-        ingress_cfg: &Option<IngressCfg>,
+        spec: &NiFiDeploymentSpec,
     ) -> Result<bool> {
         let svc = get_or_create::<Service, _>(&self.client, &name, &name, &ns, |name| {
-            self.template.nifi_service(name)
+            self.template.nifi_service(name, spec)
         });
 
         let headless_svc_name = format!("{}-headless", &name);
         let headless_svc =
             get_or_create::<Service, _>(&self.client, &headless_svc_name, &name, &ns, |name| {
-                self.template.nifi_headless_service(name)
+                self.template.nifi_headless_service(name, spec)
             });
 
+        let managed_zk = spec.managed_zookeeper();
         let zk_svc_name = format!("{}-zookeeper", &name);
-        let zk_svc = get_or_create::<Service, _>(&self.client, &zk_svc_name, &name, &ns, |name| {
-            self.template.zk_service(name)
-        });
+        let zk_svc = async {
+            if !managed_zk {
+                return Ok(Right(None));
+            }
+            get_or_create::<Service, _>(&self.client, &zk_svc_name, &name, &ns, |name| {
+                self.template.zk_service(name, spec)
+            })
+            .await
+        };
 
         let zk_headless_svc_name = format!("{}-zookeeper-headless", &name);
-        let zk_headless_svc =
+        let zk_headless_svc = async {
+            if !managed_zk {
+                return Ok(Right(None));
+            }
             get_or_create::<Service, _>(&self.client, &zk_headless_svc_name, &name, &ns, |name| {
-                self.template.zk_headless_service(name)
-            });
+                self.template.zk_headless_service(name, spec)
+            })
+            .await
+        };
 
         let ingress_name = format!("{}-ingress", &name);
         let ingress =
             get_or_create::<Ingress, _>(&self.client, &ingress_name, &name, &ns, |name| {
-                self.template.ingress(name, &ingress_cfg)
+                self.template.ingress(name, spec)
             });
 
         let (svc, headless_svc, zk_svc, zk_headless_svc, ingress) =
             futures::future::join5(svc, headless_svc, zk_svc, zk_headless_svc, ingress).await;
 
         let ingress_updated = self
-            .handle_update(&name, &ns, &ingress_cfg, &ingress_name, ingress)
+            .handle_update(&name, &ns, spec, &ingress_name, ingress)
             .await;
         vec![svc, headless_svc, zk_svc, zk_headless_svc]
             .into_iter()
@@ -71,14 +84,15 @@ impl ServiceController {
         &self,
         name: &str,
         ns: &str,
-        ingress_cfg: &Option<IngressCfg>,
+        spec: &NiFiDeploymentSpec,
         ingress_name: &str,
         ingress: Result<Either<Option<Ingress>, Option<Ingress>>>,
     ) -> Result<bool> {
-        let ingress_changed = ingress_updated(ingress, &ingress_cfg);
+        let tls_secret = ingress_tls_secret(name, spec);
+        let ingress_changed = ingress_updated(ingress, &spec.ingress, &tls_secret);
         match ingress_changed {
             Ok(true) => self
-                .recreate_ingress(&name, &ns, &ingress_name, &ingress_cfg)
+                .recreate_ingress(&name, &ns, &ingress_name, spec)
                 .await
                 .map(|_| true),
             Ok(_) => Ok(false),
@@ -91,7 +105,7 @@ impl ServiceController {
         cr_name: &str,
         ns: &str,
         ingress_name: &str,
-        ingress_cfg: &Option<IngressCfg>,
+        spec: &NiFiDeploymentSpec,
     ) -> Result<()> {
         let params = &DeleteParams::default();
         let api = get_api::<Ingress>(&self.client, &ns);
@@ -102,7 +116,7 @@ impl ServiceController {
             &cr_name,
             &ns,
             &self.client,
-            |name| self.template.ingress(name, ingress_cfg),
+            |name| self.template.ingress(name, spec),
             Ok,
         )
         .await
@@ -113,6 +127,7 @@ impl ServiceController {
 fn ingress_updated(
     current_ingress: Result<Either<Option<Ingress>, Option<Ingress>>>,
     ingress_cfg: &Option<IngressCfg>,
+    tls_secret: &Option<String>,
 ) -> Result<bool> {
     match ingress_cfg {
         Some(cfg) => {
@@ -120,6 +135,12 @@ fn ingress_updated(
             current_ingress.map(|r| match r {
                 Left(Some(ing)) => {
                     debug!("ing spec: {:?}", &ing.spec);
+                    let current_secret = ing
+                        .spec
+                        .as_ref()
+                        .and_then(|s| s.tls.as_ref())
+                        .and_then(|t| t.first())
+                        .and_then(|t| t.secret_name.clone());
                     let host_found = ing
                         .spec
                         .and_then(|s| s.rules)
@@ -139,7 +160,7 @@ fn ingress_updated(
                             .any(|(k, v)| {
                                 k == "kubernetes.io/ingress.class" && v == &cfg.ingress_class
                             });
-                    !host_found || !class_found
+                    !host_found || !class_found || &current_secret != tls_secret
                 }
                 _ => false,
             })
@@ -1,16 +1,33 @@
+use std::collections::BTreeMap;
 use std::rc::Rc;
 
 use anyhow::{Error, Result};
 use k8s_openapi::api::apps::v1::StatefulSet;
-use k8s_openapi::api::core::v1::Pod;
-use kube::api::{DeleteParams, ListParams, PostParams};
+use k8s_openapi::api::core::v1::{Container, Pod};
+use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+use kube::api::{DeleteParams, ListParams, PostParams, PropagationPolicy};
 use kube::Client;
 
+use crate::controller::canary::NiFiCanary;
+use crate::controller::migration::{
+    keep_volume_claims, migration_up_to_date, refuse_migration, storage_migration, VolumeMigration,
+};
+use crate::controller::pvc::{
+    claims_change, expand_claims, expansion_progress, size_bytes, ClaimsChange,
+};
+use crate::controller::scaling::NiFiScaling;
+use crate::controller::suspension::{initial_spec, nifi_step, pods, zk_step};
+use crate::controller::tls::set_tls_hash;
+use crate::controller::upgrade::{self, partition, NiFiUpgrade};
+use crate::controller::zookeeper::{
+    ensemble_members, ensure_auth_secret, set_ensemble_members, EnsembleReconfig,
+};
 use crate::controller::{
-    delete_resources, from_yaml, get_api, get_or_create, ConfigMapState, KUBEFI_LABELS,
-    NIFI_APP_LABEL, ZK_APP_LABEL,
+    delete_resources, from_yaml, get_api, get_or_create, get_or_create_convert, json_subset,
+    read_name, wait_deleted, ConfigMapState, StatusReport, KUBEFI_LABELS, NIFI_APP_LABEL,
+    ZK_APP_LABEL,
 };
-use crate::crd::NiFiDeployment;
+use crate::crd::{NiFiDeployment, NiFiDeploymentSpec, StorageMigration};
 use crate::template::Template;
 
 use super::either::Either::{Left, Right};
@@ -27,66 +44,148 @@ struct SetParams {
     pub image: Option<String>,
     pub set_name: String,
     pub app_label: String,
-    pub storage_class: Option<String>,
     pub cm_state: Option<ConfigMapState>,
     pub svc_updated: bool,
+    /// Pods below the partition ordinal keep the current revision
+    pub partition: Option<i32>,
 }
 
 const LOGGING_VOLUME: &str = "logback-xml";
-const NIFI_CONTAINER_NAME: &str = "server";
+pub const NIFI_CONTAINER_NAME: &str = "server";
 const ZOOKEEPER_CONTAINER_NAME: &str = "zookeeper";
 
 impl StatefulSetController {
-    async fn update_existing_set<F: FnOnce(&str, &NiFiDeployment) -> Result<Option<String>>>(
+    async fn update_existing_set(
         &self,
         d: &NiFiDeployment,
-        cr_name: &str,
         ns: &str,
         set: StatefulSet,
         params: &SetParams,
-        get_yaml: F,
+        expected_set: Result<Option<StatefulSet>>,
+        report: &StatusReport,
     ) -> Result<bool> {
+        let mut expected_set = match expected_set? {
+            Some(set) => set,
+            None => return Ok(false),
+        };
+        if let Some(partition) = params.partition {
+            set_partition(&mut expected_set, partition);
+        }
+        expansion_progress(&self.client, ns, &set, report).await?;
+
+        let mut claims_change = claims_change(&set, &expected_set)?;
+        match &claims_change {
+            ClaimsChange::StorageChanged if storage_migration(d) == StorageMigration::Copy => {
+                return self
+                    .migrate_set(d, ns, &set, params, expected_set, report)
+                    .await;
+            }
+            ClaimsChange::StorageChanged => {
+                refuse_migration(&params.set_name, report);
+                keep_volume_claims(&set, &mut expected_set);
+                claims_change = ClaimsChange::Unchanged;
+            }
+            ClaimsChange::Unchanged => migration_up_to_date(report),
+            _ => (),
+        }
+
         let image_changed = image_changed(&set, &params.image.clone(), &params.container);
         let replicas_changed = scale_set(&set, params.replicas);
-        let storage_class_changed = storage_class(&set, &params.storage_class);
+        let partition_changed = partition(&set) != partition(&expected_set);
+        let scheduling_changed = scheduling_changed(&set, &expected_set);
+        let pod_settings_changed = pod_settings_changed(&set, &expected_set)
+            || containers_changed(&set, &expected_set)
+            || resources_changed(&set, &expected_set, &params.container);
+        let storage_changed = claims_change != ClaimsChange::Unchanged;
         let logging_cm_changed =
             logging_cm(&set, params.clone().cm_state.and_then(|cm| cm.logging_cm));
 
-        if storage_class_changed {
-            let yaml = get_yaml(&cr_name, &d)?;
-            self.recreate_set(&ns, &params, yaml).await?;
+        if let ClaimsChange::Expanded(claims) = claims_change {
+            let replicas = set.spec.as_ref().and_then(|s| s.replicas).unwrap_or(0);
+            expand_claims(
+                &self.client,
+                ns,
+                &params.set_name,
+                replicas,
+                &claims,
+                report,
+            )
+            .await?;
+            // volumeClaimTemplates are immutable, so the set is recreated while Pods keep running
+            self.recreate_set(ns, params, expected_set, true).await?;
+        } else if storage_changed {
+            self.recreate_set(ns, params, expected_set, false).await?;
         } else {
-            if image_changed || replicas_changed || logging_cm_changed {
+            if image_changed
+                || replicas_changed
+                || partition_changed
+                || logging_cm_changed
+                || scheduling_changed
+                || pod_settings_changed
+            {
                 let reason = format!(
-                    "image_changed: {}, replicas_changed: {}, logging_cm_changed: {}",
-                    image_changed, replicas_changed, logging_cm_changed
+                    "image_changed: {}, replicas_changed: {}, partition_changed: {}, \
+                     logging_cm_changed: {}, scheduling_changed: {}, pod_settings_changed: {}",
+                    image_changed,
+                    replicas_changed,
+                    partition_changed,
+                    logging_cm_changed,
+                    scheduling_changed,
+                    pod_settings_changed
                 );
                 debug!(
                     "Updating existing {} statefulset with: {:?}. Reason: {}",
                     &params.set_name, &params, reason
                 );
-                let yaml = get_yaml(&cr_name, &d)?;
-                match yaml {
-                    Some(y) => self.replace_set(&ns, &params, &y).await,
-                    None => Ok(()),
-                }?;
+                self.replace_set(&ns, &params, &expected_set).await?;
             }
 
-            if image_changed
+            // partitioned sets roll out a new image themselves, one Pod at a time
+            let restart = image_changed && params.partition.is_none();
+            if restart
                 || params
                     .cm_state
                     .clone()
                     .map(|cm| cm.updated)
                     .unwrap_or(false)
             {
-                self.remove_pods(&ns, params, image_changed).await?;
+                self.remove_pods(&ns, params, restart).await?;
             }
         }
-        let state_changed =
-            storage_class_changed || image_changed || replicas_changed || logging_cm_changed;
+        let state_changed = storage_changed
+            || image_changed
+            || replicas_changed
+            || partition_changed
+            || logging_cm_changed
+            || scheduling_changed
+            || pod_settings_changed;
         Ok(state_changed)
     }
 
+    async fn migrate_set(
+        &self,
+        d: &NiFiDeployment,
+        ns: &str,
+        set: &StatefulSet,
+        params: &SetParams,
+        expected_set: StatefulSet,
+        report: &StatusReport,
+    ) -> Result<bool> {
+        let name = read_name(d)?;
+        let migration = VolumeMigration {
+            client: &self.client,
+            template: &self.template,
+            d,
+            name: &name,
+            ns,
+        };
+        if migration.migrate(set, &expected_set, report).await? {
+            self.recreate_set(ns, params, expected_set, false).await?;
+            migration.complete(&params.set_name, report).await?;
+        }
+        Ok(true)
+    }
+
     async fn remove_pods(&self, ns: &str, params: &SetParams, image_changed: bool) -> Result<()> {
         let dp = &DeleteParams::default();
         let labels = format!("app={},{}", params.app_label, KUBEFI_LABELS);
@@ -104,11 +203,15 @@ impl StatefulSetController {
         delete_resources::<Pod>(&self.client, &ns, &dp, &lp).await
     }
 
-    async fn replace_set(&self, ns: &str, set_params: &SetParams, yaml: &str) -> Result<(), Error> {
-        let new_set = from_yaml(&yaml)?;
+    async fn replace_set(
+        &self,
+        ns: &str,
+        set_params: &SetParams,
+        new_set: &StatefulSet,
+    ) -> Result<(), Error> {
         let api = get_api::<StatefulSet>(&self.client, &ns);
         let pp = PostParams::default();
-        api.replace(&set_params.set_name, &pp, &new_set)
+        api.replace(&set_params.set_name, &pp, new_set)
             .await
             .map(|_| ())
             .map_err(Error::from)
@@ -118,38 +221,28 @@ impl StatefulSetController {
         &self,
         ns: &str,
         set_params: &SetParams,
-        yaml: Option<String>,
+        new_set: StatefulSet,
+        orphan_pods: bool,
     ) -> Result<()> {
-        match yaml {
-            Some(t) => {
-                let new_set = from_yaml(&t)?;
-                let api = get_api::<StatefulSet>(&self.client, &ns);
-                let dp = DeleteParams::default();
-                api.delete(&set_params.set_name, &dp)
-                    .await
-                    .map(|_| ())
-                    .map_err(Error::from)?;
-                let pp = PostParams::default();
-                api.create(&pp, &new_set).await.map(|_| ())
-            }
-            None => Ok(()),
-        }?;
+        let api = get_api::<StatefulSet>(&self.client, &ns);
+        let dp = DeleteParams {
+            propagation_policy: if orphan_pods {
+                Some(PropagationPolicy::Orphan)
+            } else {
+                None
+            },
+            ..DeleteParams::default()
+        };
+        api.delete(&set_params.set_name, &dp)
+            .await
+            .map(|_| ())
+            .map_err(Error::from)?;
+        wait_deleted(&api, &set_params.set_name).await?;
+        let pp = PostParams::default();
+        api.create(&pp, &new_set).await?;
         Ok(())
     }
 
-    pub fn nifi_template(&self, name: &str, d: &NiFiDeployment) -> Result<Option<String>> {
-        self.template.nifi_statefulset(&name, &d.spec)
-    }
-
-    pub fn zk_template(&self, name: &str, d: &NiFiDeployment) -> Result<Option<String>> {
-        self.template.zk_statefulset(
-            &name,
-            &d.spec.zk.replicas,
-            &d.spec.zk.image,
-            &d.spec.storage_class,
-        )
-    }
-
     pub async fn handle_sets(
         &self,
         d: &NiFiDeployment,
@@ -157,62 +250,154 @@ impl StatefulSetController {
         ns: &str,
         nifi_cm_state: ConfigMapState,
         service_updated: bool,
+        report: &StatusReport,
     ) -> Result<bool> {
-        let nifi = get_or_create::<StatefulSet, _>(&self.client, &name, &name, &ns, |name| {
-            self.nifi_template(&name, &d)
-        });
+        let initial_spec = initial_spec(&d.spec);
+        let tls_hash = nifi_cm_state.tls_hash.clone();
+        let nifi = get_or_create_convert::<StatefulSet, _, _>(
+            &self.client,
+            name,
+            name,
+            ns,
+            |name| self.template.nifi_statefulset(name, &initial_spec),
+            |mut set| {
+                if let Some(hash) = &tls_hash {
+                    set_tls_hash(&mut set, hash);
+                }
+                Ok(set)
+            },
+        );
         let zk_set_name = zk_set_name(&name);
-        let get_yaml = |name: &str| self.zk_template(&name, &d);
-        let zk = get_or_create::<StatefulSet, _>(&self.client, &zk_set_name, &name, &ns, get_yaml);
+        let get_yaml = |name: &str| self.template.zk_statefulset(&name, &initial_spec);
+        let zk = async {
+            if !d.spec.managed_zookeeper() {
+                return Ok(Right(None));
+            }
+            ensure_auth_secret(&self.client, name, ns).await?;
+            get_or_create::<StatefulSet, _>(&self.client, &zk_set_name, &name, &ns, get_yaml).await
+        };
         let (nifi_res, zk_res) = futures::future::join(nifi, zk).await;
+        let zk_res = zk_res?;
+        let zk_set = match &zk_res {
+            Left(Some(set)) => Some(set),
+            _ => None,
+        };
 
+        let mut nifi_pods = 0;
         let nifi_updated = match nifi_res? {
             Left(Some(existing_set)) => {
+                nifi_pods = pods(&existing_set);
+                let step_spec = nifi_step(&d.spec, &existing_set, zk_set, report);
+                let scaling = NiFiScaling {
+                    client: &self.client,
+                    template: &self.template,
+                    name,
+                    ns,
+                };
+                let replicas = scaling.replicas(&existing_set, &step_spec, report).await?;
+                let upgrade = NiFiUpgrade {
+                    client: &self.client,
+                    template: &self.template,
+                    name,
+                    ns,
+                };
+                let target_image = self.template.nifi_image(&step_spec);
+                // a canary rollout replaces node by node upgrades
+                let (image, mut partition) = match d.spec.canary() {
+                    Some(_) => {
+                        let image = upgrade
+                            .allowed_image(&existing_set, &target_image, &d.spec, report)
+                            .await?;
+                        (image, None)
+                    }
+                    None => {
+                        let step = upgrade
+                            .step(&existing_set, &target_image, replicas, &d.spec, report)
+                            .await?;
+                        (step.image, Some(step.partition))
+                    }
+                };
+                let nifi_spec = NiFiDeploymentSpec {
+                    nifi_replicas: replicas as u8,
+                    image: Some(image.clone()),
+                    ..step_spec
+                };
+                let mut expected_set = parse_set(self.template.nifi_statefulset(name, &nifi_spec));
+                if let (Some(canary), Ok(Some(set))) = (d.spec.canary(), &expected_set) {
+                    let rollout = NiFiCanary {
+                        client: &self.client,
+                        template: &self.template,
+                        name,
+                        ns,
+                    };
+                    let set = rollout
+                        .expected_set(d, &existing_set, set.clone(), canary, report)
+                        .await?;
+                    partition = Some(upgrade::partition(&set));
+                    expected_set = Ok(Some(set));
+                }
+                // set after the canary decision, so that renewed certificates are not tried first
+                if let (Some(hash), Ok(Some(set))) = (&tls_hash, expected_set.as_mut()) {
+                    set_tls_hash(set, hash);
+                }
                 let params = SetParams {
-                    replicas: d.clone().spec.nifi_replicas as i32,
+                    replicas,
                     container: NIFI_CONTAINER_NAME.to_string(),
-                    image: d.clone().spec.image,
+                    image: Some(image),
                     set_name: name.to_string(),
                     app_label: NIFI_APP_LABEL.to_string(),
-                    storage_class: d.clone().spec.storage_class,
                     cm_state: Some(nifi_cm_state.clone()),
                     svc_updated: service_updated,
+                    partition,
                 };
-                self.update_existing_set(
-                    &d,
-                    &name,
-                    &ns,
-                    existing_set,
-                    &params,
-                    |cr_name, deployment| self.nifi_template(&cr_name, &deployment),
-                )
-                .await
+                self.update_existing_set(d, ns, existing_set, &params, expected_set, report)
+                    .await
             }
             Right(Some(_)) => Ok(true),
             _ => Ok(false),
         };
 
-        let zk_updated = match zk_res? {
+        let zk_updated = match zk_res {
             Left(Some(existing_set)) if nifi_updated.is_ok() => {
+                let mut zk_spec = zk_step(&d.spec, &existing_set, nifi_pods, report);
+                let current = existing_set
+                    .spec
+                    .as_ref()
+                    .and_then(|s| s.replicas)
+                    .unwrap_or(0);
+                let next = zk_spec.zk.replicas as i32;
+                // a suspended ensemble stops with all its members
+                if 0 < next && next < current && self.template.zk_dynamic_reconfig(&d.spec) {
+                    let reconfig = EnsembleReconfig {
+                        client: &self.client,
+                        template: &self.template,
+                        name,
+                        ns,
+                    };
+                    if !reconfig.remove_member(&d.spec, current, report).await? {
+                        zk_spec.zk.replicas = current as u8;
+                    }
+                }
                 let params = SetParams {
-                    replicas: d.clone().spec.zk.replicas as i32,
+                    replicas: zk_spec.zk.replicas as i32,
                     container: ZOOKEEPER_CONTAINER_NAME.to_string(),
                     image: d.clone().spec.zk.image,
                     set_name: zk_set_name,
                     app_label: ZK_APP_LABEL.to_string(),
-                    storage_class: d.clone().spec.storage_class,
                     cm_state: None,
                     svc_updated: false,
+                    partition: None,
                 };
-                self.update_existing_set(
-                    &d,
-                    &name,
-                    &ns,
-                    existing_set,
-                    &params,
-                    |cr_name, deployment| self.zk_template(&cr_name, &deployment),
-                )
-                .await
+                let members = ensemble_members(&existing_set);
+                let expected_set =
+                    parse_set(self.template.zk_statefulset(name, &zk_spec)).map(|set| {
+                        set.map(|mut set| {
+                            set_ensemble_members(&mut set, members);
+                            set
+                        })
+                    });
+                self.update_existing_set(d, ns, existing_set, &params, expected_set, report)
+                    .await
             }
             Right(Some(_)) => Ok(true),
             _ => Ok(false),
@@ -222,6 +407,12 @@ impl StatefulSetController {
     }
 }
 
+fn parse_set(yaml: Result<Option<String>>) -> Result<Option<StatefulSet>> {
+    yaml?
+        .map(|yaml| from_yaml::<StatefulSet>(&yaml))
+        .transpose()
+}
+
 fn zk_set_name(name: &str) -> String {
     format!("{}-zookeeper", &name)
 }
@@ -243,30 +434,113 @@ fn image_changed(set: &StatefulSet, image: &Option<String>, container: &str) ->
     }
 }
 
+pub fn set_partition(set: &mut StatefulSet, partition: i32) {
+    if let Some(spec) = set.spec.as_mut() {
+        let strategy = spec.update_strategy.get_or_insert_with(Default::default);
+        strategy
+            .rolling_update
+            .get_or_insert_with(Default::default)
+            .partition = Some(partition);
+    }
+}
+
+/// Whether Pods of the expected StatefulSet differ from the current ones
+pub fn pod_template_changed(
+    current: &StatefulSet,
+    expected: &StatefulSet,
+    container: &str,
+) -> bool {
+    scheduling_changed(current, expected)
+        || pod_settings_changed(current, expected)
+        || containers_changed(current, expected)
+        || resources_changed(current, expected, container)
+}
+
 fn scale_set(set: &StatefulSet, expected_replicas: i32) -> bool {
     let replicas = set.clone().spec.as_ref().and_then(|s| s.replicas);
     matches!(replicas, Some(current_replicas) if current_replicas != expected_replicas)
 }
 
-fn storage_class(set: &StatefulSet, storage_class: &Option<String>) -> bool {
-    match storage_class {
-        Some(sc) => set
-            .clone()
-            .spec
-            .and_then(|s| {
-                s.volume_claim_templates.map(|vc| {
-                    vc.iter().any(|pvc| {
-                        pvc.spec.clone().into_iter().any(|spec| {
-                            spec.storage_class_name
-                                .map(|scn| &scn != sc)
-                                .unwrap_or(false)
-                        })
-                    })
-                })
+fn scheduling_changed(current: &StatefulSet, expected: &StatefulSet) -> bool {
+    let scheduling = |set: &StatefulSet| {
+        set.spec
+            .as_ref()
+            .and_then(|s| s.template.spec.as_ref())
+            .map(|ps| {
+                (
+                    ps.node_selector.clone(),
+                    ps.tolerations.clone(),
+                    ps.affinity.clone(),
+                    ps.topology_spread_constraints.clone(),
+                    ps.priority_class_name.clone(),
+                )
             })
-            .unwrap_or(false),
-        None => false,
-    }
+    };
+    scheduling(current) != scheduling(expected)
+}
+
+/// Compares Pod labels and annotations.
+/// Only labels and annotations rendered by Kubefi are compared, the ones added by other tools,
+/// like the restart annotation of kubectl, are kept. Removed keys of the CR are detected
+/// by the rendered list of them. Environment is compared with the other container fields.
+fn pod_settings_changed(current: &StatefulSet, expected: &StatefulSet) -> bool {
+    let metadata = |set: &StatefulSet| {
+        let metadata = set.spec.as_ref().and_then(|s| s.template.metadata.as_ref());
+        json!({
+            "labels": metadata.and_then(|m| m.labels.clone()),
+            "annotations": metadata.and_then(|m| m.annotations.clone())
+        })
+    };
+    !json_subset(&metadata(expected), &metadata(current))
+}
+
+/// Compares volumes, containers and service account of the Pod template, including the extra
+/// ones from the CR.
+/// Expected values only have to be present in the current set, so that defaults
+/// added by the API server are not detected as changes. Resources are normalized
+/// by the API server, so their changes are detected separately.
+fn containers_changed(current: &StatefulSet, expected: &StatefulSet) -> bool {
+    let pod = |set: &StatefulSet| {
+        let pod_spec = set.spec.as_ref().and_then(|s| s.template.spec.as_ref());
+        let containers = pod_spec.map(|ps| {
+            ps.containers
+                .iter()
+                .cloned()
+                .map(|c| Container {
+                    resources: None,
+                    ..c
+                })
+                .collect::<Vec<_>>()
+        });
+        json!({
+            "volumes": pod_spec.and_then(|ps| ps.volumes.clone()),
+            "initContainers": pod_spec.and_then(|ps| ps.init_containers.clone()),
+            "containers": containers,
+            "serviceAccountName": pod_spec.and_then(|ps| ps.service_account_name.clone())
+        })
+    };
+    !json_subset(&pod(expected), &pod(current))
+}
+
+/// Compares resource quantities of the container, which are normalized by the API server
+fn resources_changed(current: &StatefulSet, expected: &StatefulSet, container: &str) -> bool {
+    let resources = |set: &StatefulSet| {
+        let resources = set
+            .spec
+            .as_ref()
+            .and_then(|s| s.template.spec.as_ref())
+            .and_then(|ps| ps.containers.iter().find(|c| c.name == container))
+            .and_then(|c| c.resources.clone())
+            .unwrap_or_default();
+        let quantities = |q: Option<BTreeMap<String, Quantity>>| {
+            q.unwrap_or_default()
+                .into_iter()
+                .map(|(k, v)| (k, size_bytes(&Some(v.0))))
+                .collect::<Vec<_>>()
+        };
+        (quantities(resources.requests), quantities(resources.limits))
+    };
+    resources(current) != resources(expected)
 }
 
 fn logging_cm(set: &StatefulSet, logging_cm: Option<String>) -> bool {
@@ -296,3 +570,94 @@ fn logging_cm(set: &StatefulSet, logging_cm: Option<String>) -> bool {
         None => false,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn set(sidecar: Value) -> StatefulSet {
+        serde_json::from_value(json!({
+            "metadata": { "name": "test" },
+            "spec": {
+                "selector": {},
+                "serviceName": "test",
+                "template": { "spec": { "containers": [
+                    { "name": "server", "image": "nifi", "resources": { "limits": { "cpu": "1" }}},
+                    sidecar
+                ]}}
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn detect_containers_change() {
+        let expected = set(json!({ "name": "proxy", "image": "envoy:1.16" }));
+        let with_defaults = set(json!({
+            "name": "proxy",
+            "image": "envoy:1.16",
+            "imagePullPolicy": "IfNotPresent",
+            "terminationMessagePath": "/dev/termination-log"
+        }));
+        assert!(!containers_changed(&with_defaults, &expected));
+
+        let updated = set(json!({ "name": "proxy", "image": "envoy:1.17" }));
+        assert!(containers_changed(&with_defaults, &updated));
+    }
+
+    #[test]
+    fn ignore_env_defaults() {
+        let env = |field_ref: Value| {
+            set(json!({
+                "name": "proxy",
+                "image": "envoy:1.16",
+                "env": [{ "name": "POD_NAMESPACE", "valueFrom": { "fieldRef": field_ref }}]
+            }))
+        };
+        let expected = env(json!({ "fieldPath": "metadata.namespace" }));
+        let with_defaults = env(json!({ "apiVersion": "v1", "fieldPath": "metadata.namespace" }));
+        assert!(!pod_template_changed(&with_defaults, &expected, "proxy"));
+
+        let updated = env(json!({ "fieldPath": "metadata.name" }));
+        assert!(pod_template_changed(&with_defaults, &updated, "proxy"));
+    }
+
+    #[test]
+    fn ignore_pod_annotations_of_other_tools() {
+        let with_metadata = |annotations: Value| {
+            let mut set = set(json!({ "name": "proxy", "image": "envoy:1.16" }));
+            set.spec.as_mut().unwrap().template.metadata = serde_json::from_value(json!({
+                "labels": { "app": "nifi" },
+                "annotations": annotations
+            }))
+            .unwrap();
+            set
+        };
+        let expected = with_metadata(json!({ "kubefi.io/pod-metadata": "{}" }));
+        let restarted = with_metadata(json!({
+            "kubefi.io/pod-metadata": "{}",
+            "kubectl.kubernetes.io/restartedAt": "2020-10-20T10:00:00Z"
+        }));
+        assert!(!pod_settings_changed(&restarted, &expected));
+
+        let updated = with_metadata(json!({ "kubefi.io/pod-metadata": "{\"annotations\":[]}" }));
+        assert!(pod_settings_changed(&restarted, &updated));
+    }
+
+    #[test]
+    fn restart_pods_on_new_certificates() {
+        let sidecar = json!({ "name": "proxy", "image": "envoy:1.16" });
+        let mut current = set(sidecar.clone());
+        set_tls_hash(&mut current, "issued");
+        // the canary decision compares sets without the hash
+        let mut expected = set(sidecar);
+        assert!(!pod_settings_changed(&current, &expected));
+
+        set_tls_hash(&mut expected, "issued");
+        assert!(!pod_settings_changed(&current, &expected));
+        set_tls_hash(&mut expected, "renewed");
+        assert!(pod_settings_changed(&current, &expected));
+    }
+}
@@ -0,0 +1,154 @@
+use k8s_openapi::api::apps::v1::StatefulSet;
+
+use crate::controller::zookeeper::{ensemble_step, has_quorum};
+use crate::controller::StatusReport;
+use crate::crd::NiFiDeploymentSpec;
+
+pub const SUSPENDED_CONDITION: &str = "Suspended";
+
+/// Spec of StatefulSets created while the cluster is suspended
+pub fn initial_spec(spec: &NiFiDeploymentSpec) -> NiFiDeploymentSpec {
+    let mut initial = spec.clone();
+    if spec.suspended {
+        initial.nifi_replicas = 0;
+        initial.zk.replicas = 0;
+    }
+    initial
+}
+
+/// Returns the spec with NiFi replicas allowed now. A suspended cluster has no NiFi nodes,
+/// and a resumed one starts its nodes only once ZooKeeper has quorum again.
+pub fn nifi_step(
+    spec: &NiFiDeploymentSpec,
+    nifi: &StatefulSet,
+    zk: Option<&StatefulSet>,
+    report: &StatusReport,
+) -> NiFiDeploymentSpec {
+    let mut step = spec.clone();
+    if spec.suspended {
+        step.nifi_replicas = 0;
+        let (nifi_pods, zk_pods) = (pods(nifi), zk.map(pods).unwrap_or(0));
+        let (reason, msg) = if nifi_pods > 0 {
+            let msg = format!("Stopping {} NiFi Pods before ZooKeeper", nifi_pods);
+            ("Suspending", msg)
+        } else if zk_pods > 0 {
+            let msg = format!("Stopping {} ZooKeeper Pods", zk_pods);
+            ("Suspending", msg)
+        } else {
+            let msg = "NiFi and ZooKeeper are scaled to zero, volumes are kept".to_string();
+            ("Suspended", msg)
+        };
+        report.set(SUSPENDED_CONDITION, true, reason, &msg);
+        return step;
+    }
+
+    let replicas = nifi.spec.as_ref().and_then(|s| s.replicas).unwrap_or(0);
+    let resuming = replicas == 0 && spec.nifi_replicas > 0;
+    match zk {
+        Some(zk) if resuming && !has_quorum(zk) => {
+            step.nifi_replicas = 0;
+            let msg = "Waiting for ZooKeeper quorum before starting NiFi";
+            report.set(SUSPENDED_CONDITION, false, "Resuming", msg);
+        }
+        _ => {
+            let suspended = report
+                .previous(SUSPENDED_CONDITION)
+                .map(|c| c.status == "True" || c.reason == "Resuming")
+                .unwrap_or(false);
+            if suspended {
+                let msg = format!("Starting {} NiFi nodes", spec.nifi_replicas);
+                report.set(SUSPENDED_CONDITION, false, "Resumed", &msg);
+            }
+        }
+    }
+    step
+}
+
+/// Returns the spec with ZooKeeper replicas of the next step. ZooKeeper of a suspended
+/// cluster is stopped once all NiFi Pods are gone.
+pub fn zk_step(
+    spec: &NiFiDeploymentSpec,
+    zk: &StatefulSet,
+    nifi_pods: i32,
+    report: &StatusReport,
+) -> NiFiDeploymentSpec {
+    if !spec.suspended {
+        return ensemble_step(zk, spec, report);
+    }
+    let mut step = spec.clone();
+    step.zk.replicas = if nifi_pods > 0 {
+        zk.spec.as_ref().and_then(|s| s.replicas).unwrap_or(0) as u8
+    } else {
+        0
+    };
+    step
+}
+
+/// Pods which still exist, including terminating ones
+pub fn pods(set: &StatefulSet) -> i32 {
+    set.status.as_ref().map(|s| s.replicas).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crd::NiFiDeploymentStatus;
+
+    fn set(replicas: i32, pods: i32, ready: i32) -> StatefulSet {
+        serde_json::from_value(json!({
+            "metadata": { "name": "test", "generation": 1 },
+            "spec": {
+                "replicas": replicas,
+                "selector": {},
+                "serviceName": "test",
+                "template": {}
+            },
+            "status": {
+                "replicas": pods,
+                "readyReplicas": ready,
+                "observedGeneration": 1,
+                "currentRevision": "r1",
+                "updateRevision": "r1"
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn stop_nifi_before_zookeeper() {
+        let report = StatusReport::new(&None::<NiFiDeploymentStatus>);
+        let mut spec = NiFiDeploymentSpec {
+            nifi_replicas: 3,
+            suspended: true,
+            ..NiFiDeploymentSpec::default()
+        };
+        spec.zk.replicas = 3;
+        let (nifi, zk) = (set(3, 3, 3), set(3, 3, 3));
+        assert_eq!(nifi_step(&spec, &nifi, Some(&zk), &report).nifi_replicas, 0);
+        assert_eq!(zk_step(&spec, &zk, pods(&nifi), &report).zk.replicas, 3);
+        assert_eq!(zk_step(&spec, &zk, 0, &report).zk.replicas, 0);
+    }
+
+    #[test]
+    fn start_nifi_after_zookeeper_quorum() {
+        let report = StatusReport::new(&None::<NiFiDeploymentStatus>);
+        let mut spec = NiFiDeploymentSpec {
+            nifi_replicas: 3,
+            ..NiFiDeploymentSpec::default()
+        };
+        spec.zk.replicas = 3;
+        let nifi = set(0, 0, 0);
+        assert_eq!(zk_step(&spec, &set(0, 0, 0), 0, &report).zk.replicas, 3);
+        let starting = set(3, 3, 1);
+        assert_eq!(
+            nifi_step(&spec, &nifi, Some(&starting), &report).nifi_replicas,
+            0
+        );
+        assert_eq!(report.conditions()[0].reason, "Resuming");
+        let quorum = set(3, 3, 2);
+        assert_eq!(
+            nifi_step(&spec, &nifi, Some(&quorum), &report).nifi_replicas,
+            3
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use anyhow::{Error, Result};
+use k8s_openapi::api::apps::v1::StatefulSet;
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::ByteString;
+use kube::api::{ListParams, Meta, ObjectMeta, PostParams};
+use kube::Client;
+
+use crate::controller::certificates::{expires_within, CertificateAuthority, MAX_CN_LENGTH};
+use crate::controller::scaling::node_identity;
+use crate::controller::{delete_if_exists, get_api, hash, StatusReport};
+use crate::crd::{NiFiDeploymentSpec, TlsMode};
+use crate::template::node_tls_secret;
+
+const CERT_KEY: &str = "tls.crt";
+const PRIVATE_KEY_KEY: &str = "tls.key";
+const CA_CERT_KEY: &str = "ca.crt";
+const CA_KEY_KEY: &str = "ca.key";
+/// Host names of the node certificate, which is issued again when they change
+const HOSTS_ANNOTATION: &str = "kubefi.io/tls-hosts";
+/// Node certificates are issued again when they expire within this period
+const RENEW_BEFORE_DAYS: u32 = 30;
+/// Pod annotation with the hash of all node certificates, so that NiFi Pods are restarted
+/// one by one to load certificates issued again
+const TLS_HASH_ANNOTATION: &str = "kubefi.io/tls-hash";
+pub const TLS_CONDITION: &str = "Certificates";
+
+/// Node certificates of both TLS modes
+pub struct TlsState {
+    pub updated: bool,
+    /// Hash of the issued node certificates, None until all of them are issued
+    pub hash: Option<String>,
+}
+
+/// Certificates of `tls.mode: generate`.
+///
+/// The CA is generated once and kept in the `<name>-tls-ca` Secret. Every NiFi node gets
+/// the `<pod>-tls` Secret with its own certificate, key and the CA certificate, the same keys
+/// as cert-manager issues. The `tls` init container reads the Secret of its host name and builds
+/// the keystores with a random password in memory on every start. They are not kept in Secrets,
+/// since Pods of a StatefulSet cannot mount a Secret of their own.
+///
+/// All NiFi Pods share one ServiceAccount, whose Role allows to read the Secrets of every node,
+/// so a node key is protected from other NiFiDeployments only, not from other nodes of the cluster.
+pub struct TlsController {
+    pub client: Rc<Client>,
+}
+
+impl TlsController {
+    pub async fn handle_tls(
+        &self,
+        name: &str,
+        ns: &str,
+        spec: &NiFiDeploymentSpec,
+        report: &StatusReport,
+    ) -> Result<TlsState> {
+        if spec.tls_mode() != Some(&TlsMode::Generate) {
+            return Ok(TlsState {
+                updated: self.delete_node_secrets(name, ns).await?,
+                hash: None,
+            });
+        }
+        if !common_names_fit(name, ns, spec, report) {
+            return Ok(TlsState {
+                updated: false,
+                hash: None,
+            });
+        }
+        let ca = self.certificate_authority(name, ns).await?;
+        let mut updated = false;
+        let mut certificates = Vec::new();
+        // Secrets of removed nodes are kept, so that the nodes can be added back
+        for ordinal in 0..spec.max_nifi_replicas() as i32 {
+            let (issued, cert) = self.node_certificate(name, ns, ordinal, &ca, spec).await?;
+            updated |= issued;
+            certificates.push(String::from_utf8_lossy(&cert).to_string());
+        }
+        // keystores of all nodes were kept in a single Secret before
+        updated |= delete_if_exists::<Secret>(&self.client, ns, &tls_secret_name(name)).await?;
+        Ok(TlsState {
+            updated,
+            hash: Some(hash(&certificates.concat())),
+        })
+    }
+
+    /// Issues the node certificate again, when it is not issued by the CA, expires soon
+    /// or its host names have changed. Returns whether it was issued and the PEM certificate.
+    async fn node_certificate(
+        &self,
+        name: &str,
+        ns: &str,
+        ordinal: i32,
+        ca: &CertificateAuthority,
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<(bool, Vec<u8>)> {
+        let api = get_api::<Secret>(&self.client, ns);
+        let secret_name = node_tls_secret(name, ordinal);
+        let current = match api.get(&secret_name).await {
+            Ok(secret) => Some(secret),
+            Err(kube::Error::Api(e)) if e.code == 404 => None,
+            Err(e) => return Err(e.into()),
+        };
+        let ca_pem = ca.cert.to_pem()?;
+        let hosts = node_hosts(name, ns, ordinal, spec);
+        let joined_hosts = hosts.join(",");
+        let valid = current
+            .as_ref()
+            .filter(|secret| {
+                let data = secret.data.clone().unwrap_or_default();
+                let current_hosts = secret
+                    .metadata
+                    .annotations
+                    .as_ref()
+                    .and_then(|a| a.get(HOSTS_ANNOTATION));
+                data.get(CA_CERT_KEY).map(|c| c.0 == ca_pem) == Some(true)
+                    && data.contains_key(PRIVATE_KEY_KEY)
+                    && data
+                        .get(CERT_KEY)
+                        .map(|c| !expires_within(&c.0, RENEW_BEFORE_DAYS))
+                        .unwrap_or(false)
+                    && current_hosts == Some(&joined_hosts)
+            })
+            .and_then(|secret| secret.data.as_ref())
+            .and_then(|data| data.get(CERT_KEY));
+        if let Some(cert) = valid {
+            return Ok((false, cert.0.clone()));
+        }
+
+        debug!("Issuing certificate of NiFi node {}-{}", name, ordinal);
+        let (cert, key) = ca.issue(&hosts)?;
+        let cert = cert.to_pem()?;
+        let mut data = BTreeMap::new();
+        data.insert(CERT_KEY.to_string(), ByteString(cert.clone()));
+        data.insert(
+            PRIVATE_KEY_KEY.to_string(),
+            ByteString(key.private_key_to_pem_pkcs8()?),
+        );
+        data.insert(CA_CERT_KEY.to_string(), ByteString(ca_pem));
+        let mut annotations = BTreeMap::new();
+        annotations.insert(HOSTS_ANNOTATION.to_string(), joined_hosts);
+        match current {
+            Some(secret) => {
+                let updated = Secret {
+                    data: Some(data),
+                    metadata: ObjectMeta {
+                        annotations: Some(annotations),
+                        ..secret.metadata
+                    },
+                    ..secret
+                };
+                debug!("Updating Secret: {}", &secret_name);
+                api.replace(&secret_name, &PostParams::default(), &updated)
+                    .await?;
+            }
+            None => {
+                let secret = Secret {
+                    metadata: ObjectMeta {
+                        name: Some(secret_name.clone()),
+                        labels: Some(labels(name)),
+                        annotations: Some(annotations),
+                        ..ObjectMeta::default()
+                    },
+                    data: Some(data),
+                    type_: Some("kubernetes.io/tls".to_string()),
+                    ..Secret::default()
+                };
+                debug!("Creating Secret: {}", &secret_name);
+                api.create(&PostParams::default(), &secret).await?;
+            }
+        }
+        Ok((true, cert))
+    }
+
+    /// Deletes node Secrets issued by the operator, once the cluster no longer uses
+    /// the generate mode. The CA is kept.
+    async fn delete_node_secrets(&self, name: &str, ns: &str) -> Result<bool> {
+        let lp = ListParams::default().labels(&instance_selector(name));
+        let secrets = get_api::<Secret>(&self.client, ns).list(&lp).await?;
+        let mut deleted = false;
+        for secret in secrets.items {
+            let issued = secret
+                .metadata
+                .annotations
+                .as_ref()
+                .map(|a| a.contains_key(HOSTS_ANNOTATION))
+                .unwrap_or(false);
+            if issued || Meta::name(&secret) == tls_secret_name(name) {
+                let secret_name = Meta::name(&secret);
+                deleted |= delete_if_exists::<Secret>(&self.client, ns, &secret_name).await?;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// CA of the NiFiDeployment, generated on first use
+    async fn certificate_authority(&self, name: &str, ns: &str) -> Result<CertificateAuthority> {
+        let api = get_api::<Secret>(&self.client, ns);
+        let secret_name = ca_secret_name(name);
+        match api.get(&secret_name).await {
+            Ok(secret) => {
+                let data = secret.data.unwrap_or_default();
+                let value = |key: &str| {
+                    data.get(key).map(|v| v.0.clone()).ok_or_else(|| {
+                        Error::msg(format!("Secret {} has no {} key", secret_name, key))
+                    })
+                };
+                CertificateAuthority::from_pem(&value(CA_CERT_KEY)?, &value(CA_KEY_KEY)?)
+            }
+            Err(kube::Error::Api(e)) if e.code == 404 => {
+                debug!("Generating CA of {}", name);
+                let ca = CertificateAuthority::generate(&format!("{}-ca", name))?;
+                let mut data = BTreeMap::new();
+                data.insert(CA_CERT_KEY.to_string(), ByteString(ca.cert.to_pem()?));
+                data.insert(CA_KEY_KEY.to_string(), ByteString(ca.key_pem()?));
+                let secret = Secret {
+                    metadata: ObjectMeta {
+                        name: Some(secret_name),
+                        labels: Some(labels(name)),
+                        ..ObjectMeta::default()
+                    },
+                    data: Some(data),
+                    type_: Some("Opaque".to_string()),
+                    ..Secret::default()
+                };
+                api.create(&PostParams::default(), &secret).await?;
+                Ok(ca)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Reports host names which are too long for the certificate CN, no certificate is issued then.
+/// The CN of a node certificate is its identity in authorizers.xml, the ingress Certificate of
+/// cert-manager has the ingress host as CN.
+pub fn common_names_fit(
+    name: &str,
+    ns: &str,
+    spec: &NiFiDeploymentSpec,
+    report: &StatusReport,
+) -> bool {
+    let mut common_names = (0..spec.max_nifi_replicas() as i32)
+        .map(|ordinal| node_identity(name, ns, ordinal))
+        .collect::<Vec<_>>();
+    if spec.tls_mode() == Some(&TlsMode::CertManager) {
+        common_names.extend(extra_hosts(spec));
+    }
+    match common_names.iter().find(|cn| cn.len() > MAX_CN_LENGTH) {
+        Some(cn) => {
+            let msg = format!(
+                "Host name {} is longer than {} characters allowed in certificate CN, \
+                shorten the name or namespace of the NiFiDeployment or the ingress host",
+                cn, MAX_CN_LENGTH
+            );
+            report.set(TLS_CONDITION, false, "HostNameTooLong", &msg);
+            false
+        }
+        None => {
+            let refused = report
+                .previous(TLS_CONDITION)
+                .map(|c| c.status == "False")
+                .unwrap_or(false);
+            if refused {
+                let msg = "Host names fit into certificate CN";
+                report.set(TLS_CONDITION, true, "HostNamesValid", msg);
+            }
+            true
+        }
+    }
+}
+
+/// Restarts NiFi Pods when the hash of node certificates changes
+pub fn set_tls_hash(set: &mut StatefulSet, hash: &str) {
+    if let Some(spec) = set.spec.as_mut() {
+        spec.template
+            .metadata
+            .get_or_insert_with(Default::default)
+            .annotations
+            .get_or_insert_with(Default::default)
+            .insert(TLS_HASH_ANNOTATION.to_string(), hash.to_string());
+    }
+}
+
+pub fn ca_secret_name(name: &str) -> String {
+    format!("{}-tls-ca", name)
+}
+
+/// Secret with the keystores of all nodes, which former versions mounted into every NiFi Pod
+pub fn tls_secret_name(name: &str) -> String {
+    format!("{}-tls", name)
+}
+
+/// Host names of the node certificate. The first one is the node identity in authorizers.xml.
+pub fn node_hosts(name: &str, ns: &str, ordinal: i32, spec: &NiFiDeploymentSpec) -> Vec<String> {
+    let mut hosts = vec![
+        node_identity(name, ns, ordinal),
+        format!("{}-{}.{}-headless.{}.svc", name, ordinal, name, ns),
+    ];
+    hosts.extend(extra_hosts(spec));
+    hosts
+}
+
+fn extra_hosts(spec: &NiFiDeploymentSpec) -> Vec<String> {
+    spec.ingress.iter().map(|i| i.host.clone()).collect()
+}
+
+/// Selects TLS resources of a single NiFiDeployment, which other ones in the namespace do not share
+pub fn instance_selector(name: &str) -> String {
+    format!(
+        "app.kubernetes.io/managed-by=Kubefi,app.kubernetes.io/instance={}",
+        name
+    )
+}
+
+pub fn labels(name: &str) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+    labels.insert(
+        "app.kubernetes.io/managed-by".to_string(),
+        "Kubefi".to_string(),
+    );
+    labels.insert("app.kubernetes.io/instance".to_string(), name.to_string());
+    labels.insert("release".to_string(), "nifi".to_string());
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crd::{IngressCfg, NiFiDeploymentStatus, Tls};
+
+    fn spec(mode: TlsMode) -> NiFiDeploymentSpec {
+        NiFiDeploymentSpec {
+            nifi_replicas: 3,
+            ingress: Some(IngressCfg {
+                host: format!("{}.example.com", "nifi".repeat(14)),
+                ingress_class: "nginx".to_string(),
+            }),
+            tls: Some(Tls {
+                mode,
+                issuer_ref: None,
+            }),
+            ..NiFiDeploymentSpec::default()
+        }
+    }
+
+    #[test]
+    fn report_host_names_too_long_for_cn() {
+        let report = StatusReport::new(&None::<NiFiDeploymentStatus>);
+        // the ingress host is only a SAN of generated node certificates
+        assert!(common_names_fit(
+            "nifi",
+            "default",
+            &spec(TlsMode::Generate),
+            &report
+        ));
+        assert!(!report.reported(TLS_CONDITION));
+        assert!(!common_names_fit(
+            "nifi",
+            "default",
+            &spec(TlsMode::CertManager),
+            &report
+        ));
+
+        let name = "n".repeat(20);
+        assert!(!common_names_fit(
+            &name,
+            "default",
+            &spec(TlsMode::Generate),
+            &report
+        ));
+        let condition = report.conditions().pop().unwrap();
+        assert_eq!(condition.reason, "HostNameTooLong");
+        assert!(condition
+            .message
+            .contains(&node_identity(&name, "default", 0)));
+    }
+}
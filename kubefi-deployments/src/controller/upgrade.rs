@@ -0,0 +1,335 @@
+use anyhow::Result;
+use chrono::Utc;
+use k8s_openapi::api::apps::v1::StatefulSet;
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::ListParams;
+use kube::Client;
+
+use crate::controller::nifi_api::{find_node, NiFiApi};
+use crate::controller::statefulset::NIFI_CONTAINER_NAME;
+use crate::controller::{get_api, StatusReport, NIFI_APP_LABEL};
+use crate::crd::NiFiDeploymentSpec;
+use crate::template::Template;
+
+pub const UPGRADE_CONDITION: &str = "NiFiUpgrade";
+pub const DEGRADED_CONDITION: &str = "Degraded";
+
+const DEFAULT_NODE_TIMEOUT_SECS: u64 = 600;
+const REVISION_LABEL: &str = "controller-revision-hash";
+
+/// Image and partition the NiFi StatefulSet is updated with
+#[derive(Debug, PartialEq)]
+pub struct UpgradeStep {
+    pub image: String,
+    pub partition: i32,
+}
+
+/// Rolls out a new NiFi image one node at a time using the StatefulSet partition.
+///
+/// The partition is lowered only after the node at the partition ordinal runs the new
+/// revision, is ready and connected to the cluster. A node which does not connect in time
+/// pauses the upgrade and marks the NiFiDeployment as Degraded. Every step is derived from
+/// the StatefulSet and Pod state, so the upgrade continues after operator restarts.
+pub struct NiFiUpgrade<'a> {
+    pub client: &'a Client,
+    pub template: &'a Template,
+    pub name: &'a str,
+    pub ns: &'a str,
+}
+
+pub enum NodeState {
+    /// Pod of the node is not recreated with the new revision yet
+    Updating,
+    /// Pod runs the new revision for the given number of seconds, but the node is not connected
+    Starting(i64),
+    Connected,
+}
+
+impl<'a> NiFiUpgrade<'a> {
+    pub async fn step(
+        &self,
+        current: &StatefulSet,
+        target_image: &str,
+        replicas: i32,
+        spec: &NiFiDeploymentSpec,
+        report: &StatusReport,
+    ) -> Result<UpgradeStep> {
+        let current_image = server_image(current).unwrap_or_default();
+        let partition = partition(current);
+        let last = (replicas - 1).max(0);
+
+        if current_image != target_image {
+            let image = self
+                .allowed_image(current, target_image, spec, report)
+                .await?;
+            if image != target_image {
+                return Ok(UpgradeStep { image, partition });
+            }
+            let msg = format!(
+                "Upgrading NiFi from {} to {}, starting with node {}-{}",
+                current_image, target_image, self.name, last
+            );
+            report.set(UPGRADE_CONDITION, true, "Upgrading", &msg);
+            return Ok(UpgradeStep {
+                image: target_image.to_string(),
+                partition: last,
+            });
+        }
+
+        let step = |partition| UpgradeStep {
+            image: target_image.to_string(),
+            partition,
+        };
+        let completed = || {
+            let msg = format!("All NiFi nodes run {}", target_image);
+            report.set(UPGRADE_CONDITION, false, "Completed", &msg);
+            self.recovered(report);
+        };
+        if partition == 0 {
+            let upgrading = report
+                .previous(UPGRADE_CONDITION)
+                .map(|c| c.status == "True")
+                .unwrap_or(false);
+            if !upgrading || !rolled_out(current, replicas) {
+                return Ok(step(0));
+            }
+            if replicas == 0 {
+                completed();
+                return Ok(step(0));
+            }
+        }
+
+        // node 0 is upgraded last, the upgrade completes once it is connected as well
+        let partition = partition.min(last);
+        let pod_name = format!("{}-{}", self.name, partition);
+        let timeout = node_timeout(spec);
+        match self.node_state(&pod_name, current, spec).await? {
+            NodeState::Connected if partition == 0 => {
+                completed();
+                Ok(step(0))
+            }
+            NodeState::Connected => {
+                let msg = format!(
+                    "Node {} is upgraded to {}, upgrading node {}-{}",
+                    pod_name,
+                    target_image,
+                    self.name,
+                    partition - 1
+                );
+                report.set(UPGRADE_CONDITION, true, "Upgrading", &msg);
+                self.recovered(report);
+                Ok(step(partition - 1))
+            }
+            NodeState::Starting(age) if age > timeout => {
+                let msg = format!(
+                    "Node {} did not connect to the cluster within {} seconds after upgrade to {}",
+                    pod_name, timeout, target_image
+                );
+                report.set(DEGRADED_CONDITION, true, "UpgradeFailed", &msg);
+                let msg = format!(
+                    "Upgrade is paused at node {}, change the image to roll back",
+                    pod_name
+                );
+                report.set(UPGRADE_CONDITION, true, "Paused", &msg);
+                Ok(step(partition))
+            }
+            _ => {
+                let msg = format!(
+                    "Waiting for node {} to connect after upgrade to {}",
+                    pod_name, target_image
+                );
+                report.set(UPGRADE_CONDITION, true, "Upgrading", &msg);
+                Ok(step(partition))
+            }
+        }
+    }
+
+    /// Returns the target image, or the current one if the target has a lower NiFi version
+    /// than running nodes and downgrades are not allowed
+    pub async fn allowed_image(
+        &self,
+        current: &StatefulSet,
+        target_image: &str,
+        spec: &NiFiDeploymentSpec,
+        report: &StatusReport,
+    ) -> Result<String> {
+        let current_image = server_image(current).unwrap_or_default();
+        let allow_downgrade = spec
+            .upgrade
+            .as_ref()
+            .map(|u| u.allow_downgrade)
+            .unwrap_or(false);
+        if current_image == target_image || allow_downgrade {
+            return Ok(target_image.to_string());
+        }
+        if let Some(running) = self.lowest_running_version().await? {
+            if version(target_image).map(|v| v < running).unwrap_or(false) {
+                let msg = format!(
+                    "Image {} has a lower NiFi version than running nodes, \
+                     set upgrade.allowDowngrade to roll it out",
+                    target_image
+                );
+                report.set(UPGRADE_CONDITION, false, "DowngradeRefused", &msg);
+                return Ok(current_image);
+            }
+        }
+        Ok(target_image.to_string())
+    }
+
+    pub async fn node_state(
+        &self,
+        pod_name: &str,
+        current: &StatefulSet,
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<NodeState> {
+        let pod = match get_api::<Pod>(self.client, self.ns).get(pod_name).await {
+            Ok(pod) => pod,
+            Err(kube::Error::Api(e)) if e.code == 404 => return Ok(NodeState::Updating),
+            Err(e) => return Err(e.into()),
+        };
+        let update_revision = current
+            .status
+            .as_ref()
+            .and_then(|s| s.update_revision.clone());
+        let revision = pod
+            .metadata
+            .labels
+            .as_ref()
+            .and_then(|l| l.get(REVISION_LABEL).cloned());
+        if revision.is_none() || revision != update_revision {
+            return Ok(NodeState::Updating);
+        }
+        let age = pod
+            .metadata
+            .creation_timestamp
+            .as_ref()
+            .map(|t| (Utc::now() - t.0).num_seconds())
+            .unwrap_or(0);
+        if !pod_ready(&pod) {
+            return Ok(NodeState::Starting(age));
+        }
+        let url = self.template.nifi_api_url(self.name, self.ns, spec);
+        let connected = async {
+            let api = NiFiApi::connect(self.client, self.name, self.ns, &url, spec).await?;
+            let nodes = api.cluster_nodes().await?;
+            let status = find_node(&nodes, pod_name).map(|n| n.status.clone());
+            Ok::<_, anyhow::Error>(status.as_deref() == Some("CONNECTED"))
+        };
+        match connected.await {
+            Ok(true) => Ok(NodeState::Connected),
+            Ok(false) => Ok(NodeState::Starting(age)),
+            Err(e) => {
+                debug!("Failed to get cluster status of node {}: {}", pod_name, e);
+                Ok(NodeState::Starting(age))
+            }
+        }
+    }
+
+    /// Lowest NiFi version of the images which NiFi Pods run
+    async fn lowest_running_version(&self) -> Result<Option<Vec<u64>>> {
+        let labels = format!(
+            "app={},app.kubernetes.io/instance={}",
+            NIFI_APP_LABEL, self.name
+        );
+        let pods = get_api::<Pod>(self.client, self.ns)
+            .list(&ListParams::default().labels(&labels))
+            .await?;
+        let lowest = pods
+            .items
+            .iter()
+            .filter_map(|p| p.spec.as_ref())
+            .flat_map(|s| s.containers.iter())
+            .filter(|c| c.name == NIFI_CONTAINER_NAME)
+            .filter_map(|c| c.image.as_deref().and_then(version))
+            .min();
+        Ok(lowest)
+    }
+
+    fn recovered(&self, report: &StatusReport) {
+        let degraded = report
+            .previous(DEGRADED_CONDITION)
+            .map(|c| c.status == "True")
+            .unwrap_or(false);
+        if degraded {
+            let msg = "Upgraded NiFi nodes are connected to the cluster";
+            report.set(DEGRADED_CONDITION, false, "Recovered", msg);
+        }
+    }
+}
+
+/// Seconds an updated node may take to connect to the cluster
+pub fn node_timeout(spec: &NiFiDeploymentSpec) -> i64 {
+    spec.upgrade
+        .as_ref()
+        .and_then(|u| u.node_timeout_secs)
+        .unwrap_or(DEFAULT_NODE_TIMEOUT_SECS) as i64
+}
+
+pub fn server_image(set: &StatefulSet) -> Option<String> {
+    set.spec
+        .as_ref()
+        .and_then(|s| s.template.spec.as_ref())
+        .and_then(|s| s.containers.iter().find(|c| c.name == NIFI_CONTAINER_NAME))
+        .and_then(|c| c.image.clone())
+}
+
+pub fn partition(set: &StatefulSet) -> i32 {
+    set.spec
+        .as_ref()
+        .and_then(|s| s.update_strategy.as_ref())
+        .and_then(|s| s.rolling_update.as_ref())
+        .and_then(|r| r.partition)
+        .unwrap_or(0)
+}
+
+fn rolled_out(set: &StatefulSet, replicas: i32) -> bool {
+    set.status
+        .as_ref()
+        .map(|s| {
+            s.updated_replicas.unwrap_or(0) >= replicas && s.ready_replicas.unwrap_or(0) >= replicas
+        })
+        .unwrap_or(false)
+}
+
+fn pod_ready(pod: &Pod) -> bool {
+    pod.status
+        .as_ref()
+        .and_then(|s| s.conditions.as_ref())
+        .map(|c| c.iter().any(|c| c.type_ == "Ready" && c.status == "True"))
+        .unwrap_or(false)
+}
+
+/// Numeric version of the image tag, for example [1, 12, 1] of apache/nifi:1.12.1
+fn version(image: &str) -> Option<Vec<u64>> {
+    let name = image.rsplit('/').next().unwrap_or_default();
+    let (_, tag) = name.split_once(':')?;
+    let version = tag
+        .split(&['.', '-'][..])
+        .map(|part| part.parse::<u64>().ok())
+        .take_while(Option::is_some)
+        .flatten()
+        .collect::<Vec<_>>();
+    if version.is_empty() {
+        None
+    } else {
+        Some(version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_image_versions() {
+        assert_eq!(version("apache/nifi:1.12.1"), Some(vec![1, 12, 1]));
+        assert_eq!(
+            version("registry:5000/apache/nifi:1.11.4-custom"),
+            Some(vec![1, 11, 4])
+        );
+        assert_eq!(version("apache/nifi:latest"), None);
+        assert_eq!(version("apache/nifi"), None);
+        assert!(version("apache/nifi:1.9.2") < version("apache/nifi:1.11.4"));
+        assert!(version("apache/nifi:2.0.0") > version("apache/nifi:1.28.1"));
+    }
+}
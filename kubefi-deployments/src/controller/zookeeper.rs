@@ -0,0 +1,303 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Error, Result};
+use k8s_openapi::api::apps::v1::StatefulSet;
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::ByteString;
+use kube::api::{DeleteParams, ObjectMeta, PostParams, PropagationPolicy};
+use kube::Client;
+use openssl::base64::encode_block;
+use openssl::sha::sha1;
+
+use crate::controller::certificates::password;
+use crate::controller::migration::{job_failed, job_succeeded};
+use crate::controller::tls::labels;
+use crate::controller::{get_api, get_or_create, StatusReport};
+use crate::crd::NiFiDeploymentSpec;
+use crate::template::Template;
+
+pub const ZK_SCALING_CONDITION: &str = "ZooKeeperScaling";
+/// True once ZooKeeper deployed by Kubefi is deleted for Kubernetes coordination
+pub const ZK_DELETED_CONDITION: &str = "ZooKeeperDeleted";
+/// Digest superuser, which reconfigures the ensemble while ACLs stay enforced
+const SUPER_USER: &str = "super";
+const SUPER_PASSWORD_KEY: &str = "superPassword";
+const SUPER_DIGEST_KEY: &str = "superDigest";
+/// Members of a stopped ensemble, which its dynamic configuration lists
+const ENSEMBLE_MEMBERS_ANNOTATION: &str = "kubefi.io/ensemble-members";
+
+/// Removes ZooKeeper members from the ensemble before the StatefulSet is scaled down.
+/// Restarts and suspension stop members without reconfiguration, so that all members
+/// keep the same configuration.
+pub struct EnsembleReconfig<'a> {
+    pub client: &'a Client,
+    pub template: &'a Template,
+    pub name: &'a str,
+    pub ns: &'a str,
+}
+
+impl<'a> EnsembleReconfig<'a> {
+    /// Returns true once the member with the given id is no longer part of the ensemble
+    pub async fn remove_member(
+        &self,
+        spec: &NiFiDeploymentSpec,
+        member: i32,
+        report: &StatusReport,
+    ) -> Result<bool> {
+        let job_name = format!("{}-zookeeper-remove-{}", self.name, member);
+        let job = get_or_create::<Job, _>(self.client, &job_name, self.name, self.ns, |name| {
+            self.template.zk_reconfig_job(name, spec, member)
+        })
+        .await?
+        .into_inner();
+        let job = match job {
+            Some(job) => job,
+            None => return Ok(false),
+        };
+        if job_failed(&job) {
+            let msg = format!(
+                "Job {} failed to remove member {} from the ZooKeeper ensemble, \
+                 delete the Job to retry",
+                job_name, member
+            );
+            report.set(ZK_SCALING_CONDITION, true, "RemoveFailed", &msg);
+            return Err(Error::msg(msg));
+        }
+        if !job_succeeded(&job) {
+            let msg = format!("Removing member {} from the ZooKeeper ensemble", member);
+            report.set(ZK_SCALING_CONDITION, true, "Scaling", &msg);
+            return Ok(false);
+        }
+        let dp = DeleteParams {
+            propagation_policy: Some(PropagationPolicy::Background),
+            ..DeleteParams::default()
+        };
+        get_api::<Job>(self.client, self.ns)
+            .delete(&job_name, &dp)
+            .await?;
+        Ok(true)
+    }
+}
+
+/// Creates the Secret with the password of the ZooKeeper superuser and its digest,
+/// which ZooKeeper servers read from DigestAuthenticationProvider.superDigest
+pub async fn ensure_auth_secret(client: &Client, name: &str, ns: &str) -> Result<()> {
+    let api = get_api::<Secret>(client, ns);
+    let secret_name = auth_secret_name(name);
+    match api.get(&secret_name).await {
+        Ok(_) => return Ok(()),
+        Err(kube::Error::Api(e)) if e.code == 404 => (),
+        Err(e) => return Err(e.into()),
+    }
+    let password = password()?;
+    let mut data = BTreeMap::new();
+    data.insert(
+        SUPER_DIGEST_KEY.to_string(),
+        ByteString(super_digest(&password).into_bytes()),
+    );
+    data.insert(
+        SUPER_PASSWORD_KEY.to_string(),
+        ByteString(password.into_bytes()),
+    );
+    let secret = Secret {
+        metadata: ObjectMeta {
+            name: Some(secret_name.clone()),
+            labels: Some(labels(name)),
+            ..ObjectMeta::default()
+        },
+        data: Some(data),
+        type_: Some("Opaque".to_string()),
+        ..Secret::default()
+    };
+    debug!("Creating Secret: {}", &secret_name);
+    api.create(&PostParams::default(), &secret).await?;
+    Ok(())
+}
+
+pub fn auth_secret_name(name: &str) -> String {
+    format!("{}-zookeeper-auth", name)
+}
+
+/// `<user>:base64(sha1(<user>:<password>))` as generated by DigestAuthenticationProvider
+fn super_digest(password: &str) -> String {
+    let hash = sha1(format!("{}:{}", SUPER_USER, password).as_bytes());
+    format!("{}:{}", SUPER_USER, encode_block(&hash))
+}
+
+/// Returns the spec with ZooKeeper replicas of the next quorum-safe scaling step.
+/// The ensemble grows or shrinks by one member at a time, and only after all current
+/// members are updated and ready. An even target size is refused. A stopped ensemble
+/// starts with all the members it had, then scales to the target.
+pub fn ensemble_step(
+    current: &StatefulSet,
+    spec: &NiFiDeploymentSpec,
+    report: &StatusReport,
+) -> NiFiDeploymentSpec {
+    let target = spec.zk.replicas as i32;
+    let replicas = current.spec.as_ref().and_then(|s| s.replicas).unwrap_or(0);
+    let mut step_spec = spec.clone();
+
+    if target % 2 == 0 && target > 0 {
+        let msg = format!(
+            "ZooKeeper ensemble size must be odd, keeping {} members instead of {}",
+            replicas, target
+        );
+        report.set(ZK_SCALING_CONDITION, false, "InvalidSize", &msg);
+        step_spec.zk.replicas = replicas as u8;
+        return step_spec;
+    }
+    let members = ensemble_members(current);
+    if replicas == 0 && members > 0 && members != target {
+        let msg = format!(
+            "Resuming ZooKeeper ensemble with its {} members, target is {}",
+            members, target
+        );
+        report.set(ZK_SCALING_CONDITION, true, "Scaling", &msg);
+        step_spec.zk.replicas = members as u8;
+        return step_spec;
+    }
+    if replicas == target || replicas == 0 {
+        let scaling = report
+            .previous(ZK_SCALING_CONDITION)
+            .map(|c| c.status == "True" || c.reason == "InvalidSize")
+            .unwrap_or(false);
+        if scaling {
+            let msg = format!("ZooKeeper ensemble has {} members", target);
+            report.set(ZK_SCALING_CONDITION, false, "Completed", &msg);
+        }
+        return step_spec;
+    }
+
+    let ready = ready_members(current);
+    let next = if ready < replicas {
+        replicas
+    } else if target > replicas {
+        replicas + 1
+    } else {
+        replicas - 1
+    };
+    let msg = if next == replicas {
+        format!(
+            "Waiting for {} of {} ZooKeeper members to be ready, scaling to {}",
+            replicas - ready,
+            replicas,
+            target
+        )
+    } else {
+        format!(
+            "Scaling ZooKeeper ensemble from {} to {} members, target is {}",
+            replicas, next, target
+        )
+    };
+    report.set(ZK_SCALING_CONDITION, true, "Scaling", &msg);
+    step_spec.zk.replicas = next as u8;
+    step_spec
+}
+
+/// Members of the ensemble. A stopped ensemble keeps their number in an annotation of the
+/// StatefulSet, because only these members are listed in the configuration on their volumes.
+pub fn ensemble_members(set: &StatefulSet) -> i32 {
+    let replicas = set.spec.as_ref().and_then(|s| s.replicas).unwrap_or(0);
+    if replicas > 0 {
+        return replicas;
+    }
+    set.metadata
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(ENSEMBLE_MEMBERS_ANNOTATION))
+        .and_then(|members| members.parse().ok())
+        .unwrap_or(0)
+}
+
+pub fn set_ensemble_members(set: &mut StatefulSet, members: i32) {
+    set.metadata
+        .annotations
+        .get_or_insert_with(Default::default)
+        .insert(ENSEMBLE_MEMBERS_ANNOTATION.to_string(), members.to_string());
+}
+
+/// Whether the majority of members run the latest revision of the StatefulSet and are ready
+pub fn has_quorum(set: &StatefulSet) -> bool {
+    let replicas = set.spec.as_ref().and_then(|s| s.replicas).unwrap_or(0);
+    replicas > 0 && ready_members(set) > replicas / 2
+}
+
+/// Members which run the latest revision of the StatefulSet and are ready
+fn ready_members(set: &StatefulSet) -> i32 {
+    let status = match &set.status {
+        Some(status) => status,
+        None => return 0,
+    };
+    let generation_observed = status.observed_generation >= set.metadata.generation;
+    let updated = status.current_revision == status.update_revision;
+    if generation_observed && updated {
+        status.ready_replicas.unwrap_or(0)
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crd::NiFiDeploymentStatus;
+
+    fn set(replicas: i32, ready: i32) -> StatefulSet {
+        serde_json::from_value(json!({
+            "metadata": { "name": "test-zookeeper", "generation": 2 },
+            "spec": {
+                "replicas": replicas,
+                "selector": {},
+                "serviceName": "test",
+                "template": {}
+            },
+            "status": {
+                "replicas": replicas,
+                "readyReplicas": ready,
+                "observedGeneration": 2,
+                "currentRevision": "r1",
+                "updateRevision": "r1"
+            }
+        }))
+        .unwrap()
+    }
+
+    fn spec(zk_replicas: u8) -> NiFiDeploymentSpec {
+        let mut spec = NiFiDeploymentSpec::default();
+        spec.zk.replicas = zk_replicas;
+        spec
+    }
+
+    #[test]
+    fn digest_of_super_user() {
+        assert_eq!(super_digest("secret"), "super:lK75jTNcA+U9vtVEw5vB51mj/w4=");
+    }
+
+    #[test]
+    fn scale_one_member_at_a_time() {
+        let report = StatusReport::new(&None::<NiFiDeploymentStatus>);
+        assert_eq!(ensemble_step(&set(1, 1), &spec(5), &report).zk.replicas, 2);
+        assert_eq!(ensemble_step(&set(2, 1), &spec(5), &report).zk.replicas, 2);
+        assert_eq!(ensemble_step(&set(5, 5), &spec(3), &report).zk.replicas, 4);
+        assert_eq!(ensemble_step(&set(3, 3), &spec(3), &report).zk.replicas, 3);
+        assert_eq!(ensemble_step(&set(3, 3), &spec(4), &report).zk.replicas, 3);
+        assert_eq!(ensemble_step(&set(0, 0), &spec(3), &report).zk.replicas, 3);
+        assert_eq!(report.conditions()[0].reason, "InvalidSize".to_string());
+    }
+
+    #[test]
+    fn resume_with_members_of_stopped_ensemble() {
+        let report = StatusReport::new(&None::<NiFiDeploymentStatus>);
+        let mut stopped = set(0, 0);
+        set_ensemble_members(&mut stopped, 5);
+        assert_eq!(ensemble_members(&stopped), 5);
+        assert_eq!(ensemble_step(&stopped, &spec(3), &report).zk.replicas, 5);
+        assert_eq!(report.conditions()[0].reason, "Scaling".to_string());
+        assert_eq!(ensemble_step(&set(5, 5), &spec(3), &report).zk.replicas, 4);
+
+        set_ensemble_members(&mut stopped, 3);
+        assert_eq!(ensemble_step(&stopped, &spec(3), &report).zk.replicas, 3);
+        assert_eq!(ensemble_step(&set(0, 0), &spec(3), &report).zk.replicas, 3);
+    }
+}
@@ -1,13 +1,20 @@
 extern crate schemars;
 extern crate serde_json;
 
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::fs;
 use std::path::PathBuf;
 
 use anyhow::Result;
-use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1beta1::{
-    CustomResourceDefinition, CustomResourceDefinitionSpec, CustomResourceValidation,
+use k8s_openapi::api::core::v1::{
+    Affinity, Container, EnvFromSource, EnvVar, ResourceRequirements, Toleration,
+    TopologySpreadConstraint, Volume, VolumeMount,
+};
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+    CustomResourceColumnDefinition, CustomResourceDefinition, CustomResourceDefinitionSpec,
+    CustomResourceDefinitionVersion, CustomResourceSubresourceScale,
+    CustomResourceSubresourceStatus, CustomResourceSubresources, CustomResourceValidation,
     JSONSchemaProps,
 };
 use kube::api::{DeleteParams, Meta, PostParams};
@@ -15,10 +22,31 @@ use kube::Api;
 use kube_derive::CustomResource;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tokio::time::{delay_for, Duration};
 
 pub const CRD_NAME: &str = "nifideployments.io.github.novakov-alexey";
 
+const PRINTER_COLUMNS: &str =
+    r#"[{"name":"Replicas", "jsonPath": ".spec.nifiReplicas", "type": "integer"}]"#;
+const SCALE: &str = r#"{"specReplicasPath":".spec.nifiReplicas",
+    "statusReplicasPath":".status.nifiReplicas", "labelSelectorPath":".status.selector"}"#;
+
+/// NiFi config values (JSON pointer into nifi.conf) which are set as structural defaults
+/// of the CRD schema (JSON pointer into schema.json), so that API server stores them in every CR.
+const SPEC_DEFAULTS: [(&str, &str); 4] = [
+    ("/image", "/properties/spec/properties/image"),
+    (
+        "/zkImage",
+        "/properties/spec/properties/zk/properties/image",
+    ),
+    ("/storageClass", "/properties/spec/properties/storageClass"),
+    (
+        "/nifiResources/jvmHeapSize",
+        "/properties/spec/properties/nifiResources/properties/jvmHeapSize",
+    ),
+];
+
 #[derive(CustomResource, Serialize, Deserialize, Default, Clone, Debug, JsonSchema)]
 #[kube(
     group = "io.github.novakov-alexey",
@@ -26,15 +54,12 @@ pub const CRD_NAME: &str = "nifideployments.io.github.novakov-alexey";
     namespaced,
     shortname = "nidp",
     status = "NiFiDeploymentStatus",
-    printcolumn = r#"{"name":"Replicas", "jsonPath": ".spec.nifiReplicas", "type": "integer"}"#,
-    apiextensions = "v1beta1"
-)]
-#[kube(
-    scale = r#"{"specReplicasPath":".spec.nifiReplicas", "statusReplicasPath":".status.nifiReplicas"}"#
+    apiextensions = "v1"
 )]
 #[serde(rename_all = "camelCase")]
 pub struct NiFiDeploymentSpec {
     pub nifi_replicas: u8,
+    #[serde(default)]
     pub zk: ZooKeeper,
     pub image: Option<String>,
     pub storage_class: Option<String>,
@@ -42,12 +67,269 @@ pub struct NiFiDeploymentSpec {
     pub logging_config_map: Option<String>,
     pub nifi_resources: Option<Resources>,
     pub ingress: Option<IngressCfg>,
+    pub nifi_properties: Option<BTreeMap<String, String>>,
+    pub overrides: Option<Value>,
+    pub storage: Option<Storage>,
+    pub storage_migration: Option<StorageMigration>,
+    pub scheduling: Option<Scheduling>,
+    pub pod_labels: Option<BTreeMap<String, String>>,
+    pub pod_annotations: Option<BTreeMap<String, String>>,
+    #[schemars(with = "Option<Vec<Value>>")]
+    pub env: Option<Vec<EnvVar>>,
+    #[schemars(with = "Option<Vec<Value>>")]
+    pub env_from: Option<Vec<EnvFromSource>>,
+    #[schemars(with = "Option<Vec<Value>>")]
+    pub extra_volumes: Option<Vec<Volume>>,
+    /// Mounted into the NiFi server container
+    #[schemars(with = "Option<Vec<Value>>")]
+    pub extra_volume_mounts: Option<Vec<VolumeMount>>,
+    #[schemars(with = "Option<Vec<Value>>")]
+    pub init_containers: Option<Vec<Container>>,
+    #[schemars(with = "Option<Vec<Value>>")]
+    pub sidecars: Option<Vec<Container>>,
+    pub extensions: Option<Vec<Extension>>,
+    pub coordination: Option<Coordination>,
+    /// Secret with username and password of a NiFi user allowed to manage the cluster,
+    /// and ca.crt, which is optional with tls.mode generate. Required for NiFi API calls to a secure cluster
+    pub api_credentials_secret: Option<String>,
+    pub autoscaling: Option<Autoscaling>,
+    /// Replica profiles applied by cron schedule instead of nifiReplicas
+    pub schedule: Option<Vec<ScheduledProfile>>,
+    /// Scales NiFi and then ZooKeeper to zero, keeping all other resources and volumes
+    #[serde(default)]
+    pub suspended: bool,
+    pub upgrade: Option<Upgrade>,
+    pub rollout: Option<Rollout>,
+    /// Number of applied specs kept as ControllerRevisions, 10 by default
+    pub revision_history_limit: Option<u8>,
+    /// Certificates of NiFi nodes, provided by the operator instead of protocol.security Secrets
+    pub tls: Option<Tls>,
+}
+
+impl NiFiDeploymentSpec {
+    /// Whether NiFi uses ZooKeeper deployed by Kubefi
+    pub fn managed_zookeeper(&self) -> bool {
+        self.zk.external.is_none() && !self.kubernetes_coordination()
+    }
+
+    pub fn kubernetes_coordination(&self) -> bool {
+        self.coordination == Some(Coordination::Kubernetes)
+    }
+
+    pub fn canary(&self) -> Option<&Canary> {
+        self.rollout.as_ref().and_then(|r| r.canary.as_ref())
+    }
+
+    pub fn tls_mode(&self) -> Option<&TlsMode> {
+        self.tls.as_ref().map(|t| &t.mode)
+    }
+
+    /// Highest number of NiFi nodes, which the autoscaler may scale up to
+    pub fn max_nifi_replicas(&self) -> u8 {
+        self.autoscaling
+            .as_ref()
+            .map(|a| a.max_replicas.max(self.nifi_replicas))
+            .unwrap_or(self.nifi_replicas)
+    }
+}
+
+/// HorizontalPodAutoscaler managed by Kubefi, which scales the NiFiDeployment
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Autoscaling {
+    pub min_replicas: Option<u8>,
+    pub max_replicas: u8,
+    /// Average CPU utilization of NiFi Pods in percent of the requested CPU
+    pub target_cpu_utilization: Option<u8>,
+    /// Average number of queued flowfiles per NiFi node
+    pub target_queued_flow_files: Option<u64>,
+    /// Number of connections with back-pressure applied
+    pub target_backpressured_connections: Option<u32>,
+    /// Additional metrics in autoscaling/v2beta2 format
+    pub metrics: Option<Vec<Value>>,
+}
+
+/// NiFi image changes are rolled out one node at a time, the highest ordinal first
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Upgrade {
+    /// Allows an image with a lower NiFi version than the running nodes have
+    #[serde(default)]
+    pub allow_downgrade: bool,
+    /// How long an upgraded node may take to connect to the cluster, before the upgrade is paused
+    pub node_timeout_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Tls {
+    pub mode: TlsMode,
+    /// Issuer of the certManager mode
+    pub issuer_ref: Option<IssuerRef>,
 }
 
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum TlsMode {
+    /// Self-signed CA and a certificate per NiFi node, kept in Secrets by the operator
+    Generate,
+    /// Certificates of NiFi nodes and the ingress issued by cert-manager
+    CertManager,
+}
+
+/// cert-manager Issuer or ClusterIssuer
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct IssuerRef {
+    pub name: String,
+    /// Issuer by default
+    pub kind: Option<String>,
+    /// cert-manager.io by default
+    pub group: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Rollout {
+    pub canary: Option<Canary>,
+}
+
+/// NiFi configuration and Pod changes are applied to the nodes at or above the partition
+/// ordinal first, and to all nodes once the canary is promoted
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Canary {
+    pub partition: u8,
+}
+
+/// Replicas which are used from the last time the cron expression fired until another profile fires
 #[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduledProfile {
+    pub name: String,
+    /// Cron expression in UTC, with optional seconds field
+    pub cron: String,
+    pub nifi_replicas: u8,
+    /// ZooKeeper replicas are not changed by the profile when empty
+    pub zk_replicas: Option<u8>,
+}
+
+/// Where NiFi cluster elects its coordinator and keeps cluster state
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Coordination {
+    ZooKeeper,
+    /// Kubernetes Leases and ConfigMaps, requires NiFi 2.0 or newer
+    Kubernetes,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct ZooKeeper {
+    #[serde(default)]
     pub replicas: u8,
     pub image: Option<String>,
+    pub storage: Option<VolumeStorage>,
+    pub scheduling: Option<Scheduling>,
+    #[schemars(with = "Option<Value>")]
+    pub resources: Option<ResourceRequirements>,
+    /// JVM heap size, for example 512m or 1G
+    pub heap_size: Option<String>,
+    pub autopurge: Option<Autopurge>,
+    /// Additional zoo.cfg entries, which take precedence over the generated ones
+    pub settings: Option<BTreeMap<String, String>>,
+    /// Existing ZooKeeper to use instead of the one deployed by Kubefi
+    pub external: Option<ExternalZooKeeper>,
+    /// Znode of the NiFi cluster state, relative to the chroot. Defaults to /nifi/<namespace>/<name>
+    /// for new clusters, clusters created before that default keep /nifi
+    pub root_node: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalZooKeeper {
+    /// Comma separated host:port list
+    pub connect_string: String,
+    /// Znode under which NiFi keeps its data
+    pub chroot: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Autopurge {
+    pub snap_retain_count: Option<u32>,
+    /// Purge task interval in hours, 0 disables purging
+    pub purge_interval: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Storage {
+    pub data: Option<VolumeStorage>,
+    pub flowfile_repository: Option<VolumeStorage>,
+    pub content_repository: Option<VolumeStorage>,
+    pub provenance_repository: Option<VolumeStorage>,
+    pub logs: Option<VolumeStorage>,
+}
+
+/// Pod scheduling constraints. Pods of the same instance prefer different nodes, unless affinity is set
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Scheduling {
+    pub node_selector: Option<BTreeMap<String, String>>,
+    #[schemars(with = "Option<Vec<Value>>")]
+    pub tolerations: Option<Vec<Toleration>>,
+    #[schemars(with = "Option<Value>")]
+    pub affinity: Option<Affinity>,
+    #[schemars(with = "Option<Vec<Value>>")]
+    pub topology_spread_constraints: Option<Vec<TopologySpreadConstraint>>,
+    pub priority_class_name: Option<String>,
+}
+
+/// Custom NARs registered as an additional NiFi library directory.
+/// Exactly one source has to be set.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Extension {
+    pub name: String,
+    pub persistent_volume_claim: Option<ExtensionClaim>,
+    /// ConfigMap with NAR files as binaryData
+    pub config_map: Option<String>,
+    pub image: Option<ExtensionImage>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtensionClaim {
+    pub claim_name: String,
+    /// Directory with NARs inside the volume
+    pub path: Option<String>,
+}
+
+/// Image with NARs, which are copied by an init container
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtensionImage {
+    pub image: String,
+    /// Directory with NARs inside the image
+    pub path: String,
+}
+
+/// What to do when storage class or access modes of existing volumes are changed
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+pub enum StorageMigration {
+    /// Keep existing volumes and report StorageMigration condition
+    Refuse,
+    /// Stop the Pods and copy data of every replica to new volumes
+    Copy,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VolumeStorage {
+    pub size: Option<String>,
+    pub storage_class: Option<String>,
+    pub access_modes: Option<Vec<String>>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
@@ -76,19 +358,52 @@ pub struct PodResources {
     pub memory: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct NiFiDeploymentStatus {
+    /// NiFi Pods created by the StatefulSet
     pub nifi_replicas: u8,
+    /// Label selector of NiFi Pods, used by the scale subresource
+    #[serde(default)]
+    pub selector: String,
+    /// Scheduled profile, which replicas are applied
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_profile: Option<String>,
+    /// Revision of the last applied spec
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_revision: Option<i64>,
+    /// Revision applied before the current one, which the spec can be rolled back to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_revision: Option<i64>,
+    /// ZooKeeper root node used while zk.rootNode is unset, so that the default never moves cluster state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zk_root_node: Option<String>,
     pub error_msg: String,
+    #[serde(default)]
+    pub conditions: Vec<Condition>,
 }
 
-pub async fn replace_crd(crds: Api<CustomResourceDefinition>, schema: PathBuf) -> Result<()> {
+#[derive(Deserialize, Serialize, Clone, Debug, Default, JsonSchema, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Condition {
+    #[serde(rename = "type")]
+    pub condition_type: String,
+    pub status: String,
+    pub reason: String,
+    pub message: String,
+    pub last_transition_time: String,
+}
+
+pub async fn replace_crd(
+    crds: Api<CustomResourceDefinition>,
+    schema: PathBuf,
+    nifi_cfg: &Value,
+) -> Result<()> {
     delete_old_version(crds.clone()).await?;
     delay_for(Duration::from_secs(2)).await;
 
     let schema = fs::read_to_string(schema)?;
-    create_new_version(crds, schema).await?;
+    create_new_version(crds, schema, nifi_cfg).await?;
     delay_for(Duration::from_secs(1)).await;
     Ok(())
 }
@@ -117,9 +432,12 @@ async fn delete_old_version(crds: Api<CustomResourceDefinition>) -> Result<()> {
 async fn create_new_version(
     crds: Api<CustomResourceDefinition>,
     json_schema: String,
+    nifi_cfg: &Value,
 ) -> Result<()> {
-    let schema: JSONSchemaProps = serde_json::from_str(&json_schema)?;
-    let crd = with_schema(schema, NiFiDeployment::crd());
+    let mut schema: Value = serde_json::from_str(&json_schema)?;
+    add_defaults(&mut schema, nifi_cfg);
+    let schema: JSONSchemaProps = serde_json::from_value(schema)?;
+    let crd = with_schema(schema, NiFiDeployment::crd())?;
     debug!("Creating CRD: {}", serde_json::to_string_pretty(&crd)?);
     let pp = PostParams::default();
     match crds.create(&pp, &crd).await {
@@ -135,16 +453,49 @@ async fn create_new_version(
     }
 }
 
-fn with_schema(schema: JSONSchemaProps, crd: CustomResourceDefinition) -> CustomResourceDefinition {
-    CustomResourceDefinition {
-        spec: CustomResourceDefinitionSpec {
-            validation: Some(CustomResourceValidation {
-                open_api_v3_schema: Some(schema),
+fn add_defaults(schema: &mut Value, nifi_cfg: &Value) {
+    for (cfg_path, schema_path) in SPEC_DEFAULTS.iter() {
+        let default = nifi_cfg.pointer(cfg_path).filter(|v| !v.is_null());
+        match (default, schema.pointer_mut(schema_path)) {
+            (Some(value), Some(Value::Object(property))) => {
+                debug!("Setting default for {} to {}", schema_path, value);
+                property.insert("default".to_string(), value.clone());
+            }
+            (Some(_), _) => warn!("Schema property {} is not found", schema_path),
+            _ => (),
+        }
+    }
+}
+
+fn with_schema(
+    schema: JSONSchemaProps,
+    crd: CustomResourceDefinition,
+) -> Result<CustomResourceDefinition> {
+    let columns: Vec<CustomResourceColumnDefinition> = serde_json::from_str(PRINTER_COLUMNS)?;
+    let scale: CustomResourceSubresourceScale = serde_json::from_str(SCALE)?;
+    let versions = crd
+        .spec
+        .versions
+        .into_iter()
+        .map(|v| CustomResourceDefinitionVersion {
+            schema: Some(CustomResourceValidation {
+                open_api_v3_schema: Some(schema.clone()),
+            }),
+            subresources: Some(CustomResourceSubresources {
+                scale: Some(scale.clone()),
+                status: Some(CustomResourceSubresourceStatus(json!({}))),
             }),
+            additional_printer_columns: Some(columns.clone()),
+            ..v
+        })
+        .collect();
+    Ok(CustomResourceDefinition {
+        spec: CustomResourceDefinitionSpec {
+            versions,
             ..crd.spec
         },
         ..crd
-    }
+    })
 }
 
 #[cfg(test)]
@@ -160,4 +511,58 @@ mod tests {
         let schema = schema_for!(NiFiDeploymentSpec);
         println!("{}", serde_json::to_string_pretty(&schema).unwrap());
     }
+
+    #[test]
+    fn schema_defaults() {
+        let json_schema = fs::read_to_string("./conf/schema.json").expect("Failed to read schema");
+        let mut schema: Value = serde_json::from_str(&json_schema).unwrap();
+        let config = crate::config::read_nifi_config().expect("Failed to load config");
+        add_defaults(&mut schema, &config);
+
+        let spec = schema.pointer("/properties/spec/properties").unwrap();
+        assert_eq!(spec["image"]["default"], config["image"]);
+        assert_eq!(
+            spec["zk"]["properties"]["image"]["default"],
+            config["zkImage"]
+        );
+        assert_eq!(
+            spec["nifiResources"]["properties"]["jvmHeapSize"]["default"],
+            config["nifiResources"]["jvmHeapSize"]
+        );
+        let crd = with_schema(
+            serde_json::from_value(schema).unwrap(),
+            NiFiDeployment::crd(),
+        )
+        .expect("Failed to build CRD");
+        assert!(crd.spec.versions.iter().all(|v| v.schema.is_some()));
+    }
+
+    #[test]
+    fn cr_without_zk_gets_zk_image() {
+        let json_schema = fs::read_to_string("./conf/schema.json").expect("Failed to read schema");
+        let mut schema: Value = serde_json::from_str(&json_schema).unwrap();
+        let config = crate::config::read_nifi_config().expect("Failed to load config");
+        add_defaults(&mut schema, &config);
+
+        let mut spec = json!({ "nifiReplicas": 1 });
+        apply_defaults(&schema["properties"]["spec"], &mut spec);
+        let spec: NiFiDeploymentSpec = serde_json::from_value(spec).unwrap();
+        assert_eq!(spec.zk.image.as_deref(), config["zkImage"].as_str());
+    }
+
+    /// Applies schema defaults the way API server does: absent properties get their default,
+    /// then defaulting continues into the nested objects
+    fn apply_defaults(schema: &Value, value: &mut Value) {
+        if let (Some(properties), Value::Object(object)) = (schema["properties"].as_object(), value)
+        {
+            for (name, property) in properties {
+                if let Some(default) = property.get("default") {
+                    object.entry(name).or_insert_with(|| default.clone());
+                }
+                if let Some(nested) = object.get_mut(name) {
+                    apply_defaults(property, nested);
+                }
+            }
+        }
+    }
 }
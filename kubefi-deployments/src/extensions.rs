@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Error, Result};
+use serde_json::Value;
+
+use crate::crd::Extension;
+
+/// Directory of the NiFi server container where extensions are mounted
+const EXTENSIONS_DIR: &str = "/opt/nifi/extensions";
+const NAR_DIRECTORY_PROPERTY: &str = "nifi.nar.library.directory";
+
+/// Volumes, server container mounts and init containers of the NiFi Pod for the extensions
+#[derive(Default)]
+pub struct ExtensionResources {
+    pub volumes: Vec<Value>,
+    pub volume_mounts: Vec<Value>,
+    pub init_containers: Vec<Value>,
+}
+
+pub fn extension_resources(extensions: &[Extension]) -> Result<ExtensionResources> {
+    let mut resources = ExtensionResources::default();
+    for ext in extensions {
+        let volume_name = format!("extension-{}", ext.name);
+        let mut mount =
+            json!({ "name": volume_name, "mountPath": nar_directory(ext), "readOnly": true });
+        let mut volume = match (&ext.persistent_volume_claim, &ext.config_map, &ext.image) {
+            (Some(pvc), None, None) => {
+                if let Some(path) = &pvc.path {
+                    mount["subPath"] = json!(path.trim_start_matches('/'));
+                }
+                json!({ "persistentVolumeClaim": { "claimName": pvc.claim_name, "readOnly": true }})
+            }
+            (None, Some(cm), None) => json!({ "configMap": { "name": cm }}),
+            (None, None, Some(image)) => {
+                resources.init_containers.push(json!({
+                    "name": volume_name,
+                    "image": image.image,
+                    "command": ["sh", "-c", format!("cp -r {}/. /extension/", image.path)],
+                    "volumeMounts": [{ "name": volume_name, "mountPath": "/extension" }]
+                }));
+                json!({ "emptyDir": {} })
+            }
+            _ => {
+                return Err(Error::msg(format!(
+                "Extension {} must have exactly one of persistentVolumeClaim, configMap or image",
+                ext.name
+            )))
+            }
+        };
+        volume["name"] = json!(volume_name);
+        resources.volumes.push(volume);
+        resources.volume_mounts.push(mount);
+    }
+    Ok(resources)
+}
+
+/// Additional `nifi.nar.library.directory.<name>` properties of the extensions
+pub fn nar_directories(extensions: &[Extension]) -> BTreeMap<String, String> {
+    extensions
+        .iter()
+        .map(|ext| {
+            (
+                format!("{}.{}", NAR_DIRECTORY_PROPERTY, ext.name),
+                nar_directory(ext),
+            )
+        })
+        .collect()
+}
+
+fn nar_directory(ext: &Extension) -> String {
+    format!("{}/{}", EXTENSIONS_DIR, ext.name)
+}
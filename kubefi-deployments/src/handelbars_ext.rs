@@ -53,6 +53,21 @@ pub fn get_files_helper(
     }
 }
 
+/// Writes the parameter as inline JSON, which is valid YAML at any position
+pub fn to_json_helper(
+    h: &Helper,
+    _: &Handlebars,
+    _: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = h
+        .param(0)
+        .ok_or_else(|| RenderError::new("to_json helper requires a parameter"))?;
+    out.write(&value.value().to_string())?;
+    Ok(())
+}
+
 fn excluded(entry: &Result<DirEntry, Error>, files: &[String]) -> bool {
     entry
         .as_ref()
@@ -107,7 +122,17 @@ fn format_line(indent_param: usize, f: &File, l: Result<String, Error>) -> Strin
 mod tests {
     use crate::crd::PodResources;
     use crate::crd::Resources;
-    use crate::crd::{NiFiDeploymentSpec, ZooKeeper};
+    use crate::crd::{
+        Autoscaling, Coordination, IngressCfg, IssuerRef, NiFiDeploymentSpec, Storage, Tls,
+        TlsMode, VolumeStorage, ZooKeeper,
+    };
+    use k8s_openapi::api::apps::v1::StatefulSet;
+    use k8s_openapi::api::autoscaling::v2beta2::HorizontalPodAutoscaler;
+    use k8s_openapi::api::batch::v1::Job;
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use k8s_openapi::api::networking::v1beta1::Ingress;
+    use k8s_openapi::api::rbac::v1::{Role, RoleBinding};
+    use serde_json::Value;
     use std::path::Path;
 
     use crate::template::Template;
@@ -124,6 +149,655 @@ mod tests {
         println!("content:\n{}", content.unwrap())
     }
 
+    #[test]
+    fn override_nifi_properties() {
+        let template = template();
+        let mut spec = test_spec(None);
+        let props = vec![
+            ("nifi.queue.swap.threshold", "40000"),
+            (
+                "nifi.content.repository.archive.max.retention.period",
+                "1 day",
+            ),
+            ("nifi.custom.property", "custom"),
+        ];
+        spec.nifi_properties = Some(
+            props
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        );
+        let content = template
+            .nifi_configmap("test", "test", &spec)
+            .expect("Failed to render configmap template")
+            .unwrap();
+        let cm: ConfigMap = serde_yaml::from_str(&content).unwrap();
+        let nifi_properties = cm.data.unwrap()["nifi.properties"].clone();
+        for (k, v) in props {
+            let line = format!("{}={}", k, v);
+            assert_eq!(nifi_properties.lines().filter(|l| l == &line).count(), 1);
+            let prefix = format!("{}=", k);
+            assert_eq!(
+                nifi_properties
+                    .lines()
+                    .filter(|l| l.starts_with(&prefix))
+                    .count(),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn overrides_global_config() {
+        let template = template();
+        let mut spec = test_spec(None);
+        spec.image = Some("apache/nifi:1.11.4".to_string());
+        spec.overrides = Some(json!({ "protocol": { "isSecure": false, "httpPort": 8081 }}));
+        let content = template
+            .nifi_statefulset("test", &spec)
+            .expect("Failed to render statefulset template")
+            .unwrap();
+        let set: StatefulSet = serde_yaml::from_str(&content).unwrap();
+        let ports = set.spec.unwrap().template.spec.unwrap().containers[0]
+            .ports
+            .clone()
+            .unwrap();
+        assert!(ports.iter().any(|p| p.container_port == 8081));
+        assert!(ports.iter().all(|p| p.name != Some("https".to_string())));
+    }
+
+    #[test]
+    fn generated_tls() {
+        let template = template();
+        let mut spec = test_spec(None);
+        spec.overrides = Some(json!({ "protocol": { "isSecure": false }}));
+        spec.tls = Some(Tls {
+            mode: TlsMode::Generate,
+            issuer_ref: None,
+        });
+        let content = template
+            .nifi_statefulset("test", &spec)
+            .expect("Failed to render statefulset template")
+            .unwrap();
+        let set: StatefulSet = serde_yaml::from_str(&content).unwrap();
+        let pod_spec = set.spec.unwrap().template.spec.unwrap();
+        // keystores are built from the Secret of the Pod, no Secret is shared by all Pods
+        let volumes = pod_spec.volumes.unwrap();
+        assert!(volumes.iter().all(|v| v.secret.is_none()));
+        let jks = volumes.iter().find(|v| v.name == "nifi-tls-jks").unwrap();
+        assert!(jks.empty_dir.is_some());
+        let init = pod_spec.init_containers.unwrap();
+        let tls = init.iter().find(|c| c.name == "tls").unwrap();
+        let script = tls.command.as_ref().unwrap().join("\n");
+        assert!(script.contains("secrets/$(hostname)-tls"));
+        assert_eq!(pod_spec.service_account_name, Some("test".to_string()));
+        let server = &pod_spec.containers[0];
+        let script = server.command.as_ref().unwrap().join("\n");
+        assert!(script.contains("conf/keystorePasswd"));
+        let mounts = server.volume_mounts.clone().unwrap();
+        assert!(mounts.iter().any(|m| m.name == "nifi-tls-jks"));
+        assert!(mounts.iter().any(|m| m.name == "no-api-access"));
+        let ports = server.ports.clone().unwrap();
+        assert!(ports.iter().any(|p| p.name == Some("https".to_string())));
+
+        spec.autoscaling = Some(Autoscaling {
+            max_replicas: 3,
+            ..Autoscaling::default()
+        });
+        let content = template.nifi_role("test", &spec).unwrap().unwrap();
+        let role: Role = serde_yaml::from_str(&content).unwrap();
+        let rules = role.rules.unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(
+            rules[0].resource_names,
+            Some(vec![
+                "test-0-tls".to_string(),
+                "test-1-tls".to_string(),
+                "test-2-tls".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn cert_manager_tls() {
+        let template = template();
+        let mut spec = test_spec(None);
+        spec.ingress = Some(IngressCfg {
+            host: "nifi.example.com".to_string(),
+            ingress_class: "nginx".to_string(),
+        });
+        spec.tls = Some(Tls {
+            mode: TlsMode::CertManager,
+            issuer_ref: Some(IssuerRef {
+                name: "ca-issuer".to_string(),
+                kind: Some("ClusterIssuer".to_string()),
+                group: None,
+            }),
+        });
+        let hosts = vec!["test-0.test-headless.ns.svc.cluster.local".to_string()];
+        let content = template
+            .nifi_certificate("test", "test-0", &hosts, &spec)
+            .expect("Failed to render certificate template")
+            .unwrap();
+        let certificate: Value = serde_yaml::from_str(&content).unwrap();
+        assert_eq!(certificate["spec"]["secretName"], json!("test-0-tls"));
+        assert_eq!(
+            certificate["spec"]["secretTemplate"]["labels"]["app.kubernetes.io/instance"],
+            json!("test")
+        );
+        assert_eq!(certificate["spec"]["commonName"], json!(hosts[0]));
+        assert_eq!(
+            certificate["spec"]["issuerRef"],
+            json!({ "name": "ca-issuer", "kind": "ClusterIssuer", "group": "cert-manager.io" })
+        );
+
+        let content = template
+            .ingress("test", &spec)
+            .expect("Failed to render ingress template")
+            .unwrap();
+        let ingress: Ingress = serde_yaml::from_str(&content).unwrap();
+        let tls = ingress.spec.unwrap().tls.unwrap();
+        assert_eq!(tls[0].secret_name, Some("test-ingress-tls".to_string()));
+
+        let content = template
+            .nifi_statefulset("test", &spec)
+            .expect("Failed to render statefulset template")
+            .unwrap();
+        let set: StatefulSet = serde_yaml::from_str(&content).unwrap();
+        let volumes = set.spec.unwrap().template.spec.unwrap().volumes.unwrap();
+        assert!(volumes.iter().all(|v| v.secret.is_none()));
+    }
+
+    #[test]
+    fn volume_storage() {
+        let template = template();
+        let mut spec = test_spec(None);
+        spec.storage_class = Some("standard".to_string());
+        spec.storage = Some(Storage {
+            provenance_repository: Some(VolumeStorage {
+                size: Some("200Gi".to_string()),
+                storage_class: Some("fast-ssd".to_string()),
+                access_modes: None,
+            }),
+            ..Storage::default()
+        });
+        let content = template
+            .nifi_statefulset("test", &spec)
+            .expect("Failed to render statefulset template")
+            .unwrap();
+        let set: StatefulSet = serde_yaml::from_str(&content).unwrap();
+        let claims = set.spec.unwrap().volume_claim_templates.unwrap();
+        for pvc in claims {
+            let pvc_spec = pvc.spec.unwrap();
+            let size = pvc_spec.resources.unwrap().requests.unwrap()["storage"].clone();
+            let storage_class = pvc_spec.storage_class_name.unwrap();
+            assert_eq!(pvc_spec.access_modes.unwrap(), vec!["ReadWriteOnce"]);
+            match pvc.metadata.name.unwrap().as_str() {
+                "provenance-repository" => {
+                    assert_eq!(size.0, "200Gi");
+                    assert_eq!(storage_class, "fast-ssd");
+                }
+                "data" => {
+                    assert_eq!(size.0, "512Mi");
+                    assert_eq!(storage_class, "standard");
+                }
+                _ => assert_eq!(storage_class, "standard"),
+            }
+        }
+    }
+
+    #[test]
+    fn scheduling() {
+        let template = template();
+        let mut spec = test_spec(None);
+        spec.zk.scheduling = serde_json::from_value(serde_json::json!({
+            "nodeSelector": { "pool": "zookeeper" },
+            "tolerations": [{ "key": "dedicated", "operator": "Equal", "value": "zk", "effect": "NoSchedule" }],
+            "priorityClassName": "high"
+        }))
+        .unwrap();
+
+        let content = template
+            .nifi_statefulset("test", &spec)
+            .expect("Failed to render statefulset template")
+            .unwrap();
+        let set: StatefulSet = serde_yaml::from_str(&content).unwrap();
+        let pod_spec = set.spec.unwrap().template.spec.unwrap();
+        let term = pod_spec
+            .affinity
+            .and_then(|a| a.pod_anti_affinity)
+            .and_then(|a| a.preferred_during_scheduling_ignored_during_execution)
+            .unwrap()
+            .remove(0)
+            .pod_affinity_term;
+        let labels = term.label_selector.and_then(|s| s.match_labels).unwrap();
+        assert_eq!(labels["app"], "nifi");
+        assert_eq!(labels["app.kubernetes.io/instance"], "test");
+        assert!(pod_spec.node_selector.is_none());
+
+        let content = template
+            .zk_statefulset("test", &spec)
+            .expect("Failed to render statefulset template")
+            .unwrap();
+        let set: StatefulSet = serde_yaml::from_str(&content).unwrap();
+        let pod_spec = set.spec.unwrap().template.spec.unwrap();
+        assert_eq!(pod_spec.node_selector.unwrap()["pool"], "zookeeper");
+        assert_eq!(
+            pod_spec.tolerations.unwrap()[0].value,
+            Some("zk".to_string())
+        );
+        assert_eq!(pod_spec.priority_class_name, Some("high".to_string()));
+        assert!(pod_spec.affinity.is_some());
+    }
+
+    #[test]
+    fn pod_settings() {
+        let template = template();
+        let spec = NiFiDeploymentSpec {
+            pod_labels: serde_json::from_value(
+                serde_json::json!({ "team": "data", "app": "other" }),
+            )
+            .unwrap(),
+            pod_annotations: serde_json::from_value(
+                serde_json::json!({ "vault.hashicorp.com/agent-inject": "true" }),
+            )
+            .unwrap(),
+            env: serde_json::from_value(serde_json::json!([{ "name": "TZ", "value": "UTC" }]))
+                .unwrap(),
+            env_from: serde_json::from_value(
+                serde_json::json!([{ "secretRef": { "name": "proxy" } }]),
+            )
+            .unwrap(),
+            ..test_spec(None)
+        };
+        let content = template
+            .nifi_statefulset("test", &spec)
+            .expect("Failed to render statefulset template")
+            .unwrap();
+        let set: StatefulSet = serde_yaml::from_str(&content).unwrap();
+        let pod = set.spec.unwrap().template;
+        let metadata = pod.metadata.unwrap();
+        let labels = metadata.labels.unwrap();
+        assert_eq!(labels["team"], "data");
+        assert_eq!(labels["app"], "nifi");
+        assert_eq!(
+            metadata.annotations.unwrap()["vault.hashicorp.com/agent-inject"],
+            "true"
+        );
+        let container = pod.spec.unwrap().containers.remove(0);
+        let env = container.env.unwrap();
+        assert_eq!(env.len(), 2);
+        assert_eq!(env[1].value, Some("UTC".to_string()));
+        let env_from = container.env_from.unwrap();
+        assert_eq!(
+            env_from[0].secret_ref.as_ref().unwrap().name,
+            Some("proxy".to_string())
+        );
+    }
+
+    #[test]
+    fn extra_volumes_and_containers() {
+        let template = template();
+        let spec = NiFiDeploymentSpec {
+            extra_volumes: serde_json::from_value(serde_json::json!([
+                { "name": "jdbc", "emptyDir": {} }
+            ]))
+            .unwrap(),
+            extra_volume_mounts: serde_json::from_value(serde_json::json!([
+                { "name": "jdbc", "mountPath": "/opt/jdbc" }
+            ]))
+            .unwrap(),
+            init_containers: serde_json::from_value(serde_json::json!([
+                { "name": "drivers", "image": "drivers:1.0" }
+            ]))
+            .unwrap(),
+            sidecars: serde_json::from_value(serde_json::json!([
+                { "name": "proxy", "image": "envoy:1.16" }
+            ]))
+            .unwrap(),
+            ..test_spec(None)
+        };
+        let content = template
+            .nifi_statefulset("test", &spec)
+            .expect("Failed to render statefulset template")
+            .unwrap();
+        let set: StatefulSet = serde_yaml::from_str(&content).unwrap();
+        let pod_spec = set.spec.unwrap().template.spec.unwrap();
+        assert_eq!(pod_spec.volumes.unwrap().last().unwrap().name, "jdbc");
+        let init_containers = pod_spec.init_containers.unwrap();
+        assert_eq!(init_containers.last().unwrap().name, "drivers");
+        assert_eq!(pod_spec.containers.last().unwrap().name, "proxy");
+        let server_mounts = pod_spec.containers[0].volume_mounts.clone().unwrap();
+        assert_eq!(server_mounts.last().unwrap().mount_path, "/opt/jdbc");
+    }
+
+    #[test]
+    fn extensions() {
+        let template = template();
+        let spec = NiFiDeploymentSpec {
+            extensions: serde_json::from_value(serde_json::json!([
+                { "name": "shared", "persistentVolumeClaim": { "claimName": "nars", "path": "team-a" }},
+                { "name": "custom", "image": { "image": "custom-nars:1.0", "path": "/nars" }}
+            ]))
+            .unwrap(),
+            ..test_spec(None)
+        };
+        let content = template
+            .nifi_statefulset("test", &spec)
+            .expect("Failed to render statefulset template")
+            .unwrap();
+        let set: StatefulSet = serde_yaml::from_str(&content).unwrap();
+        let pod_spec = set.spec.unwrap().template.spec.unwrap();
+        let volumes = pod_spec.volumes.unwrap();
+        let claim = volumes[volumes.len() - 2].persistent_volume_claim.clone();
+        assert_eq!(claim.unwrap().claim_name, "nars");
+        assert!(volumes.last().unwrap().empty_dir.is_some());
+        let init = pod_spec.init_containers.unwrap().pop().unwrap();
+        assert_eq!(init.image, Some("custom-nars:1.0".to_string()));
+        let mounts = pod_spec.containers[0].volume_mounts.clone().unwrap();
+        assert_eq!(
+            mounts[mounts.len() - 2].sub_path,
+            Some("team-a".to_string())
+        );
+
+        let content = template
+            .nifi_configmap("test", "default", &spec)
+            .expect("Failed to render configmap template")
+            .unwrap();
+        let cm: ConfigMap = serde_yaml::from_str(&content).unwrap();
+        let props = cm.data.unwrap()["nifi.properties"].clone();
+        assert!(props.contains("nifi.nar.library.directory.custom=/opt/nifi/extensions/custom"));
+        assert!(props.contains("nifi.nar.library.directory=./lib"));
+
+        let invalid = NiFiDeploymentSpec {
+            extensions: serde_json::from_value(serde_json::json!([{ "name": "empty" }])).unwrap(),
+            ..test_spec(None)
+        };
+        assert!(template.nifi_statefulset("test", &invalid).is_err());
+    }
+
+    #[test]
+    fn zookeeper_settings() {
+        let template = template();
+        let mut spec = test_spec(None);
+        spec.zk.heap_size = Some("768m".to_string());
+        spec.zk.resources =
+            serde_json::from_value(serde_json::json!({ "limits": { "memory": "1Gi" }})).unwrap();
+        spec.zk.settings =
+            serde_json::from_value(serde_json::json!({ "snapCount": "50000" })).unwrap();
+
+        let mut quoted = spec.clone();
+        quoted.zk.settings =
+            serde_json::from_value(serde_json::json!({ "note": "it's quoted" })).unwrap();
+        let content = template
+            .zk_configmap("test", &quoted)
+            .expect("Failed to render configmap template")
+            .unwrap();
+        assert!(content.contains(r#"echo 'note=it'\''s quoted' >> $ZK_CONFIG_FILE"#));
+
+        let content = template
+            .zk_statefulset("test", &spec)
+            .expect("Failed to render statefulset template")
+            .unwrap();
+        let set: StatefulSet = serde_yaml::from_str(&content).unwrap();
+        let pod = set.spec.unwrap().template;
+        let annotations = pod.metadata.unwrap().annotations.unwrap();
+        assert_eq!(annotations["kubefi.io/zoo-cfg"], r#"{"snapCount":"50000"}"#);
+        let container = pod.spec.unwrap().containers.remove(0);
+        let env = container.env.unwrap();
+        let value = |name: &str| env.iter().find(|e| e.name == name).unwrap().value.clone();
+        assert_eq!(value("ZK_HEAP_SIZE"), Some("768m".to_string()));
+        assert_eq!(value("ZK_PURGE_INTERVAL"), Some("24".to_string()));
+        let limits = container.resources.unwrap().limits.unwrap();
+        assert_eq!(limits["memory"].0, "1Gi");
+        assert_eq!(value("ZK_DYNAMIC_RECONFIG"), Some("true".to_string()));
+        assert!(env.iter().all(|e| e.name != "ZK_REPLICAS"));
+
+        spec.zk.image = Some("zookeeper:3.4.14".to_string());
+        spec.zk.replicas = 3;
+        let content = template
+            .zk_statefulset("test", &spec)
+            .expect("Failed to render statefulset template")
+            .unwrap();
+        let set: StatefulSet = serde_yaml::from_str(&content).unwrap();
+        let env = set.spec.unwrap().template.spec.unwrap().containers[0]
+            .env
+            .clone()
+            .unwrap();
+        let value = |name: &str| env.iter().find(|e| e.name == name).unwrap().value.clone();
+        assert_eq!(value("ZK_REPLICAS"), Some("3".to_string()));
+        assert_eq!(value("ZK_DYNAMIC_RECONFIG"), Some("false".to_string()));
+
+        let content = template
+            .zk_configmap("test", &spec)
+            .expect("Failed to render configmap template")
+            .unwrap();
+        let cm: ConfigMap = serde_yaml::from_str(&content).unwrap();
+        assert!(cm.data.unwrap()["run"].contains("echo 'snapCount=50000' >> $ZK_CONFIG_FILE"));
+    }
+
+    #[test]
+    fn zookeeper_member_removal() {
+        let template = template();
+        let mut spec = test_spec(None);
+        spec.zk.replicas = 3;
+        let content = template
+            .zk_reconfig_job("test", &spec, 3)
+            .expect("Failed to render job template")
+            .unwrap();
+        let job: Job = serde_yaml::from_str(&content).unwrap();
+        assert_eq!(
+            job.metadata.name,
+            Some("test-zookeeper-remove-3".to_string())
+        );
+        let container = job
+            .spec
+            .unwrap()
+            .template
+            .spec
+            .unwrap()
+            .containers
+            .remove(0);
+        assert!(container.command.unwrap()[2].contains("reconfig -remove 3"));
+        let env = container.env.unwrap();
+        let secret = env[0].value_from.as_ref().unwrap().secret_key_ref.as_ref();
+        assert_eq!(
+            secret.unwrap().name,
+            Some("test-zookeeper-auth".to_string())
+        );
+
+        let content = template
+            .zk_statefulset("test", &spec)
+            .expect("Failed to render statefulset template")
+            .unwrap();
+        let set: StatefulSet = serde_yaml::from_str(&content).unwrap();
+        let container = set
+            .spec
+            .unwrap()
+            .template
+            .spec
+            .unwrap()
+            .containers
+            .remove(0);
+        assert!(container.lifecycle.is_none());
+        let env = container.env.unwrap();
+        assert!(env.iter().any(|e| e.name == "ZK_SUPER_DIGEST"));
+    }
+
+    #[test]
+    fn external_zookeeper() {
+        let template = template();
+        let mut spec = test_spec(None);
+        spec.zk.external = serde_json::from_value(serde_json::json!({
+            "connectString": "zk-0.zk:2181,zk-1.zk:2182/shared"
+        }))
+        .unwrap();
+
+        let content = template
+            .nifi_configmap("test", "default", &spec)
+            .expect("Failed to render configmap template")
+            .unwrap();
+        let cm: ConfigMap = serde_yaml::from_str(&content).unwrap();
+        let data = cm.data.unwrap();
+        let props = data["nifi.properties"].clone();
+        assert!(props.contains("nifi.zookeeper.connect.string=zk-0.zk:2181,zk-1.zk:2182\n"));
+        assert!(props.contains("nifi.zookeeper.root.node=/shared/nifi/default/test\n"));
+        assert!(data["state-management.xml"]
+            .contains(r#"<property name="Root Node">/shared/nifi/default/test</property>"#));
+
+        let content = template
+            .nifi_statefulset("test", &spec)
+            .expect("Failed to render statefulset template")
+            .unwrap();
+        let set: StatefulSet = serde_yaml::from_str(&content).unwrap();
+        let pod_spec = set.spec.unwrap().template.spec.unwrap();
+        let env = pod_spec.containers[0].env.clone().unwrap();
+        let connect = env
+            .iter()
+            .find(|e| e.name == "NIFI_ZOOKEEPER_CONNECT_STRING")
+            .unwrap();
+        assert_eq!(connect.value, Some("zk-0.zk:2181,zk-1.zk:2182".to_string()));
+        let wait = pod_spec.init_containers.unwrap()[0]
+            .command
+            .clone()
+            .unwrap();
+        assert!(wait
+            .join(" ")
+            .contains("nc -vzw 1 zk-0.zk 2181 || nc -vzw 1 zk-1.zk 2182 || false"));
+
+        spec.zk.external = serde_json::from_value(serde_json::json!({
+            "connectString": "zk:2181", "chroot": "/team-a/"
+        }))
+        .unwrap();
+        let content = template
+            .nifi_configmap("test", "default", &spec)
+            .expect("Failed to render configmap template")
+            .unwrap();
+        assert!(content.contains("nifi.zookeeper.root.node=/team-a/nifi/default/test"));
+
+        spec.zk.root_node = Some("analytics/".to_string());
+        let content = template
+            .nifi_configmap("test", "default", &spec)
+            .expect("Failed to render configmap template")
+            .unwrap();
+        assert!(content.contains("nifi.zookeeper.root.node=/team-a/analytics"));
+        spec.zk.external = None;
+        let content = template
+            .nifi_configmap("test", "default", &spec)
+            .expect("Failed to render configmap template")
+            .unwrap();
+        assert!(content.contains("nifi.zookeeper.root.node=/analytics"));
+
+        spec.zk.root_node = Some("/".to_string());
+        assert!(template.nifi_configmap("test", "default", &spec).is_err());
+    }
+
+    #[test]
+    fn kubernetes_coordination() {
+        let template = template();
+        let mut spec = test_spec(None);
+        assert!(template.nifi_role("test", &spec).unwrap().is_none());
+
+        spec.coordination = Some(Coordination::Kubernetes);
+        let content = template
+            .nifi_configmap("test", "default", &spec)
+            .expect("Failed to render configmap template")
+            .unwrap();
+        let cm: ConfigMap = serde_yaml::from_str(&content).unwrap();
+        let data = cm.data.unwrap();
+        let props = data["nifi.properties"].clone();
+        assert!(props.contains("nifi.state.management.provider.cluster=kubernetes-provider"));
+        assert!(props.contains("nifi.cluster.leader.election.kubernetes.lease.prefix=test"));
+        assert!(data["state-management.xml"].contains("KubernetesConfigMapStateProvider"));
+
+        let content = template
+            .nifi_statefulset("test", &spec)
+            .expect("Failed to render statefulset template")
+            .unwrap();
+        let set: StatefulSet = serde_yaml::from_str(&content).unwrap();
+        let pod_spec = set.spec.unwrap().template.spec.unwrap();
+        assert_eq!(pod_spec.service_account_name, Some("test".to_string()));
+        assert!(pod_spec.init_containers.is_none());
+
+        let content = template.nifi_role_binding("test", &spec).unwrap().unwrap();
+        let binding: RoleBinding = serde_yaml::from_str(&content).unwrap();
+        assert_eq!(binding.role_ref.name, "test");
+        assert_eq!(binding.subjects.unwrap()[0].name, "test");
+    }
+
+    #[test]
+    fn horizontal_pod_autoscaler() {
+        let template = template();
+        let mut spec = test_spec(None);
+        assert!(template.nifi_hpa("test", "ns", &spec).unwrap().is_none());
+
+        spec.autoscaling = Some(Autoscaling {
+            min_replicas: None,
+            max_replicas: 5,
+            target_cpu_utilization: Some(70),
+            target_queued_flow_files: Some(10000),
+            target_backpressured_connections: None,
+            metrics: None,
+        });
+        let content = template
+            .nifi_hpa("test", "ns", &spec)
+            .expect("Failed to render hpa template")
+            .unwrap();
+        let hpa: HorizontalPodAutoscaler = serde_yaml::from_str(&content).unwrap();
+        let hpa_spec = hpa.spec.unwrap();
+        assert_eq!(hpa_spec.scale_target_ref.kind, "NiFiDeployment");
+        assert_eq!(hpa_spec.min_replicas, Some(1));
+        assert_eq!(hpa_spec.max_replicas, 5);
+        let metrics = hpa_spec.metrics.unwrap();
+        assert_eq!(metrics.len(), 2);
+        let external = metrics[1].external.as_ref().unwrap();
+        assert_eq!(external.metric.name, "kubefi_nifi_flowfiles_queued");
+        let labels = external
+            .metric
+            .selector
+            .as_ref()
+            .unwrap()
+            .match_labels
+            .as_ref();
+        assert_eq!(labels.unwrap()["name"], "test");
+    }
+
+    #[test]
+    fn volume_copy_job() {
+        let template = template();
+        let volumes = vec!["data".to_string(), "flowfile-repository".to_string()];
+        let content = template
+            .volume_copy_job("test", &test_spec(None), "test", 1, &volumes)
+            .expect("Failed to render job template")
+            .unwrap();
+        let job: Job = serde_yaml::from_str(&content).unwrap();
+        assert_eq!(job.metadata.name.unwrap(), "test-volume-copy-1");
+        assert_eq!(
+            job.metadata.labels.unwrap()["app.kubernetes.io/instance"],
+            "test"
+        );
+        let claims = job
+            .spec
+            .unwrap()
+            .template
+            .spec
+            .unwrap()
+            .volumes
+            .unwrap()
+            .into_iter()
+            .filter_map(|v| v.persistent_volume_claim.map(|pvc| pvc.claim_name))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            claims,
+            vec![
+                "data-test-1",
+                "data-test-1-migration",
+                "flowfile-repository-test-1",
+                "flowfile-repository-test-1-migration"
+            ]
+        );
+    }
+
     #[test]
     fn print_statefulset() {
         let config = super::super::config::read_nifi_config().expect("Failed to load config");
@@ -148,12 +822,25 @@ mod tests {
         println!("content:\n{}", content.unwrap())
     }
 
+    fn template() -> Template {
+        let config = super::super::config::read_nifi_config().expect("Failed to load config");
+        Template::new(Path::new("./templates"), config).expect("Failed to create template engine")
+    }
+
     fn test_spec(res: Option<Resources>) -> NiFiDeploymentSpec {
         NiFiDeploymentSpec {
             nifi_replicas: 2,
             zk: ZooKeeper {
                 replicas: 2,
                 image: None,
+                storage: None,
+                scheduling: None,
+                resources: None,
+                heap_size: None,
+                autopurge: None,
+                settings: None,
+                external: None,
+                root_node: None,
             },
             image: None,
             storage_class: None,
@@ -161,6 +848,29 @@ mod tests {
             logging_config_map: None,
             nifi_resources: res,
             ingress: None,
+            nifi_properties: None,
+            overrides: None,
+            storage: None,
+            storage_migration: None,
+            scheduling: None,
+            pod_labels: None,
+            pod_annotations: None,
+            env: None,
+            env_from: None,
+            extra_volumes: None,
+            extra_volume_mounts: None,
+            init_containers: None,
+            sidecars: None,
+            extensions: None,
+            coordination: None,
+            api_credentials_secret: None,
+            autoscaling: None,
+            schedule: None,
+            suspended: false,
+            upgrade: None,
+            rollout: None,
+            revision_history_limit: None,
+            tls: None,
         }
     }
 }
@@ -17,7 +17,9 @@ use crate::Namespace::*;
 pub mod config;
 pub mod controller;
 pub mod crd;
+mod extensions;
 mod handelbars_ext;
+pub mod metrics;
 pub mod template;
 pub mod watcher;
 
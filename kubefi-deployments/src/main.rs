@@ -13,13 +13,15 @@ use std::rc::Rc;
 use anyhow::Result;
 use dotenv::dotenv;
 use futures::StreamExt;
-use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1beta1::CustomResourceDefinition;
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::api::{Api, ListParams};
 use kube::Client;
+use tokio::time::Duration;
 
 use kubefi_deployments::config::{read_kubefi_config, read_nifi_config};
 use kubefi_deployments::controller::NiFiController;
 use kubefi_deployments::crd::{replace_crd, NiFiDeployment};
+use kubefi_deployments::metrics::{serve, Metrics};
 use kubefi_deployments::template::Template;
 use kubefi_deployments::watcher::watch;
 use kubefi_deployments::{get_api, read_namespace, read_type};
@@ -42,23 +44,33 @@ async fn main() -> Result<()> {
     let kubefi_cfg = read_kubefi_config()?;
     debug!(">>>> Loaded Kubefi config {:?}", kubefi_cfg);
     let client = Client::try_default().await?;
+    let nifi_cfg = read_nifi_config()?;
+    debug!(">>>> Loaded NiFi config {}", &nifi_cfg);
 
     let crds: Api<CustomResourceDefinition> = Api::all(client.clone());
     if kubefi_cfg.replace_existing_crd {
-        replace_crd(crds, kubefi_cfg.crd_schema_path).await?;
+        replace_crd(crds, kubefi_cfg.crd_schema_path, &nifi_cfg).await?;
     }
 
     let namespace = read_namespace();
     let api = get_api::<NiFiDeployment>(&namespace, client.clone());
 
     let mut watcher = kube_runtime::watcher(api.clone(), ListParams::default()).boxed();
-    let nifi_cfg = read_nifi_config()?;
-    debug!(">>>> Loaded NiFi config {}", &nifi_cfg);
+
+    let metrics = Metrics::default();
+    let metrics_port = kubefi_cfg.metrics_port;
+    let served = metrics.clone();
+    tokio::spawn(async move {
+        if let Err(e) = serve(metrics_port, served).await {
+            error!("Failed to serve metrics: {}", e);
+        }
+    });
 
     let controller = NiFiController::new(
         namespace,
         Rc::new(client.clone()),
         Rc::new(Template::new(Path::new("./templates"), nifi_cfg)?),
+        metrics,
     )?;
 
     info!(
@@ -66,5 +78,6 @@ async fn main() -> Result<()> {
         read_type::<NiFiDeployment>("NiFi")
     );
 
-    watch(client, &mut watcher, &controller).await
+    let resync_interval = Duration::from_secs(kubefi_cfg.resync_interval_secs);
+    watch(client, &mut watcher, &controller, resync_interval).await
 }
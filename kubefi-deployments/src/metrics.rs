@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+
+/// NiFi cluster state collected during reconciliation
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClusterMetrics {
+    pub replicas: i32,
+    pub flow_files_queued: Option<u64>,
+    pub bytes_queued: Option<u64>,
+    pub backpressured_connections: Option<u32>,
+}
+
+/// Metrics of all NiFiDeployments, exposed in Prometheus text format,
+/// so that HorizontalPodAutoscalers can use them through a metrics adapter
+#[derive(Clone, Default)]
+pub struct Metrics {
+    clusters: Arc<Mutex<BTreeMap<(String, String), ClusterMetrics>>>,
+}
+
+impl Metrics {
+    pub fn record(&self, ns: &str, name: &str, metrics: ClusterMetrics) {
+        if let Ok(mut clusters) = self.clusters.lock() {
+            clusters.insert((ns.to_string(), name.to_string()), metrics);
+        }
+    }
+
+    pub fn remove(&self, ns: &str, name: &str) {
+        if let Ok(mut clusters) = self.clusters.lock() {
+            clusters.remove(&(ns.to_string(), name.to_string()));
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let clusters = match self.clusters.lock() {
+            Ok(clusters) => clusters.clone(),
+            Err(_) => return String::new(),
+        };
+        let mut out = String::new();
+        let mut gauge =
+            |metric: &str, help: &str, value: &dyn Fn(&ClusterMetrics) -> Option<u64>| {
+                let _ = writeln!(out, "# HELP {} {}", metric, help);
+                let _ = writeln!(out, "# TYPE {} gauge", metric);
+                for ((ns, name), m) in clusters.iter() {
+                    if let Some(v) = value(m) {
+                        let _ = writeln!(
+                            out,
+                            "{}{{namespace=\"{}\",name=\"{}\"}} {}",
+                            metric, ns, name, v
+                        );
+                    }
+                }
+            };
+        gauge(REPLICAS_METRIC, "NiFi Pods of the cluster", &|m| {
+            Some(m.replicas.max(0) as u64)
+        });
+        gauge(
+            FLOW_FILES_QUEUED_METRIC,
+            "Flowfiles queued in the cluster",
+            &|m| m.flow_files_queued,
+        );
+        gauge(BYTES_QUEUED_METRIC, "Bytes queued in the cluster", &|m| {
+            m.bytes_queued
+        });
+        gauge(
+            BACKPRESSURED_CONNECTIONS_METRIC,
+            "Connections with back-pressure applied",
+            &|m| m.backpressured_connections.map(u64::from),
+        );
+        out
+    }
+}
+
+pub const REPLICAS_METRIC: &str = "kubefi_nifi_replicas";
+pub const FLOW_FILES_QUEUED_METRIC: &str = "kubefi_nifi_flowfiles_queued";
+pub const BYTES_QUEUED_METRIC: &str = "kubefi_nifi_bytes_queued";
+pub const BACKPRESSURED_CONNECTIONS_METRIC: &str = "kubefi_nifi_backpressured_connections";
+
+/// Serves the metrics on /metrics of the given port
+pub async fn serve(port: u16, metrics: Metrics) -> Result<()> {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let metrics = metrics.clone();
+                async move {
+                    let response = if req.uri().path() == "/metrics" {
+                        Response::new(Body::from(metrics.render()))
+                    } else {
+                        let mut not_found = Response::new(Body::empty());
+                        *not_found.status_mut() = hyper::StatusCode::NOT_FOUND;
+                        not_found
+                    };
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+    info!("Serving metrics on {}", addr);
+    Server::bind(&addr).serve(make_service).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_prometheus_text() {
+        let metrics = Metrics::default();
+        metrics.record(
+            "test",
+            "nifi",
+            ClusterMetrics {
+                replicas: 3,
+                flow_files_queued: Some(1200),
+                bytes_queued: None,
+                backpressured_connections: Some(2),
+            },
+        );
+        let text = metrics.render();
+        assert!(text.contains("kubefi_nifi_replicas{namespace=\"test\",name=\"nifi\"} 3\n"));
+        assert!(
+            text.contains("kubefi_nifi_flowfiles_queued{namespace=\"test\",name=\"nifi\"} 1200")
+        );
+        assert!(!text.contains("kubefi_nifi_bytes_queued{"));
+        metrics.remove("test", "nifi");
+        assert!(!metrics.render().contains("{namespace"));
+    }
+}
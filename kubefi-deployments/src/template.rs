@@ -1,13 +1,22 @@
+use std::collections::BTreeMap;
 use std::path::Path;
 
 use anyhow::{Error, Result};
 use handlebars::Handlebars;
+use k8s_openapi::api::core::v1::ConfigMap;
+use serde::Serialize;
 use serde_json::Value;
 
+use crate::crd::Autoscaling;
 use crate::crd::IngressCfg;
+use crate::crd::IssuerRef;
 use crate::crd::NiFiDeploymentSpec;
 use crate::crd::PodResources;
-use crate::handelbars_ext::get_files_helper;
+use crate::crd::Scheduling;
+use crate::crd::TlsMode;
+use crate::extensions::{extension_resources, nar_directories};
+use crate::handelbars_ext::{get_files_helper, to_json_helper};
+use crate::metrics::{BACKPRESSURED_CONNECTIONS_METRIC, FLOW_FILES_QUEUED_METRIC};
 
 pub struct Template {
     handlebars: Handlebars<'static>,
@@ -24,14 +33,34 @@ const ZK_STATEFULSET: &str = "zk-statefulset";
 const ZK_SERVICE: &str = "zk-service";
 const ZK_HEADLESS_SERVICE: &str = "zk-headless-service";
 const ZK_CONFIGMAP: &str = "zk-configmap";
+const VOLUME_COPY_JOB: &str = "volume-copy-job";
+const ZK_RECONFIG_JOB: &str = "zk-reconfig-job";
+const NIFI_SERVICE_ACCOUNT: &str = "nifi-serviceaccount";
+const NIFI_ROLE: &str = "nifi-role";
+const NIFI_ROLE_BINDING: &str = "nifi-rolebinding";
+const NIFI_HPA: &str = "nifi-hpa";
+const NIFI_CERTIFICATE: &str = "nifi-certificate";
+
+const NIFI_APP: &str = "nifi";
+const ZK_APP: &str = "zookeeper";
+const ZK_CLIENT_PORT: u16 = 2181;
+const INSTANCE_LABEL: &str = "app.kubernetes.io/instance";
+const RESERVED_LABELS: [&str; 4] = [
+    "app",
+    "release",
+    "app.kubernetes.io/managed-by",
+    INSTANCE_LABEL,
+];
 
 const TEMPLATE_FILE_EXTENSION: &str = ".yaml";
+const NIFI_PROPERTIES: &str = "nifi.properties";
 
 impl Template {
     pub fn new(path: &Path, config: Value) -> Result<Template> {
         let mut handlebars = Handlebars::new();
         handlebars.register_templates_directory(TEMPLATE_FILE_EXTENSION, path)?;
         handlebars.register_helper("get_files", Box::new(get_files_helper));
+        handlebars.register_helper("to_json", Box::new(to_json_helper));
         handlebars.set_strict_mode(true);
         Ok(Template { handlebars, config })
     }
@@ -66,51 +95,155 @@ impl Template {
             merge_json(&mut data, limits);
         }
 
-        self.statefulset(
-            name,
-            &spec.nifi_replicas,
-            data,
-            &spec.storage_class,
-            NIFI_STATEFULSET,
-        )
+        if let Some(storage) = &spec.storage {
+            merge_json(&mut data, json!({ "storage": spec_json(storage) }));
+        }
+        merge_json(&mut data, scheduling(&spec.scheduling, name, NIFI_APP));
+        merge_json(&mut data, pod_settings(spec)?);
+
+        self.statefulset(name, &spec.nifi_replicas, data, spec, NIFI_STATEFULSET)
+    }
+
+    /// NiFi image of the spec or the default one of nifi.conf
+    pub fn nifi_image(&self, spec: &NiFiDeploymentSpec) -> String {
+        spec.image
+            .clone()
+            .or_else(|| self.config["image"].as_str().map(String::from))
+            .unwrap_or_default()
+    }
+
+    pub fn zk_statefulset(&self, name: &str, spec: &NiFiDeploymentSpec) -> Result<Option<String>> {
+        let mut data = json!({ "zkImage": spec.zk.image });
+        if let Some(storage) = &spec.zk.storage {
+            merge_json(&mut data, json!({ "zk": { "storage": spec_json(storage) }}));
+        }
+        merge_json(&mut data, scheduling(&spec.zk.scheduling, name, ZK_APP));
+        merge_json(&mut data, zk_settings(spec));
+        merge_json(
+            &mut data,
+            json!({ "zk": { "dynamicReconfig": self.zk_dynamic_reconfig(spec) }}),
+        );
+        self.statefulset(name, &spec.zk.replicas, data, spec, ZK_STATEFULSET)
     }
 
-    pub fn zk_statefulset(
+    /// ZooKeeper image of the spec or the default one of nifi.conf
+    fn zk_image(&self, spec: &NiFiDeploymentSpec) -> String {
+        spec.zk
+            .image
+            .clone()
+            .or_else(|| self.config["zkImage"].as_str().map(String::from))
+            .unwrap_or_default()
+    }
+
+    /// Whether ZooKeeper members are added and removed by dynamic reconfiguration
+    pub fn zk_dynamic_reconfig(&self, spec: &NiFiDeploymentSpec) -> bool {
+        dynamic_reconfig(&self.zk_image(spec))
+    }
+
+    /// Job removing the ZooKeeper member with the given id from the ensemble
+    pub fn zk_reconfig_job(
         &self,
         name: &str,
-        replicas: &u8,
-        image_name: &Option<String>,
-        storage_class: &Option<String>,
+        spec: &NiFiDeploymentSpec,
+        member: i32,
     ) -> Result<Option<String>> {
-        let image = json!({ "zkImage": image_name });
-        self.statefulset(name, replicas, image, storage_class, ZK_STATEFULSET)
+        let mut data = self.get_config(name, spec);
+        merge_json(
+            &mut data,
+            json!({ "zkImage": self.zk_image(spec), "member": member }),
+        );
+        self.render(&data, ZK_RECONFIG_JOB)
     }
 
-    pub fn nifi_service(&self, name: &str) -> Result<Option<String>> {
-        self.service(name, NIFI_SERVICE)
+    pub fn nifi_service(&self, name: &str, spec: &NiFiDeploymentSpec) -> Result<Option<String>> {
+        self.service(name, spec, NIFI_SERVICE)
     }
 
-    pub fn nifi_headless_service(&self, name: &str) -> Result<Option<String>> {
-        self.service(name, NIFI_HEADLESS_SERVICE)
+    pub fn nifi_headless_service(
+        &self,
+        name: &str,
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<Option<String>> {
+        self.service(name, spec, NIFI_HEADLESS_SERVICE)
     }
 
-    pub fn zk_service(&self, name: &str) -> Result<Option<String>> {
-        self.service(name, ZK_SERVICE)
+    pub fn zk_service(&self, name: &str, spec: &NiFiDeploymentSpec) -> Result<Option<String>> {
+        self.service(name, spec, ZK_SERVICE)
     }
 
-    pub fn zk_headless_service(&self, name: &str) -> Result<Option<String>> {
-        self.service(name, ZK_HEADLESS_SERVICE)
+    pub fn zk_headless_service(
+        &self,
+        name: &str,
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<Option<String>> {
+        self.service(name, spec, ZK_HEADLESS_SERVICE)
     }
 
-    fn service(&self, name: &str, template: &str) -> Result<Option<String>> {
-        let data = self.get_config(name);
+    fn service(
+        &self,
+        name: &str,
+        spec: &NiFiDeploymentSpec,
+        template: &str,
+    ) -> Result<Option<String>> {
+        let data = self.get_config(name, spec);
         debug!("service template {} params\n:{}", &template, &data);
         self.render(&data, template)
     }
 
-    pub fn ingress(&self, name: &str, cfg: &Option<IngressCfg>) -> Result<Option<String>> {
-        let mut data = self.get_config(name);
-        if let Some(ing) = cfg {
+    pub fn nifi_service_account(
+        &self,
+        name: &str,
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<Option<String>> {
+        self.rbac(name, spec, NIFI_SERVICE_ACCOUNT)
+    }
+
+    pub fn nifi_role(&self, name: &str, spec: &NiFiDeploymentSpec) -> Result<Option<String>> {
+        self.rbac(name, spec, NIFI_ROLE)
+    }
+
+    pub fn nifi_role_binding(
+        &self,
+        name: &str,
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<Option<String>> {
+        self.rbac(name, spec, NIFI_ROLE_BINDING)
+    }
+
+    /// RBAC resources are rendered only for Kubernetes coordination or TLS of the operator
+    fn rbac(
+        &self,
+        name: &str,
+        spec: &NiFiDeploymentSpec,
+        template: &str,
+    ) -> Result<Option<String>> {
+        let data = self.get_config(name, spec);
+        debug!("rbac template {} params\n:{}", &template, &data);
+        self.render(&data, template)
+    }
+
+    pub fn nifi_hpa(
+        &self,
+        name: &str,
+        ns: &str,
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<Option<String>> {
+        let mut data = self.get_config(name, spec);
+        if let Some(autoscaling) = &spec.autoscaling {
+            let hpa = json!({ "autoscaling": {
+                "minReplicas": autoscaling.min_replicas.unwrap_or(1),
+                "maxReplicas": autoscaling.max_replicas,
+                "metrics": autoscaling_metrics(name, ns, autoscaling)
+            }});
+            merge_json(&mut data, hpa);
+        }
+        debug!("hpa template params\n:{}", &data);
+        self.render(&data, NIFI_HPA)
+    }
+
+    pub fn ingress(&self, name: &str, spec: &NiFiDeploymentSpec) -> Result<Option<String>> {
+        let mut data = self.get_config(name, spec);
+        if let Some(ing) = &spec.ingress {
             let json = Template::add_ingress(ing);
             merge_json(&mut data, json);
         }
@@ -118,6 +251,24 @@ impl Template {
         self.render(&data, INGRESS)
     }
 
+    /// cert-manager Certificate, which first host is the common name
+    pub fn nifi_certificate(
+        &self,
+        name: &str,
+        certificate_name: &str,
+        hosts: &[String],
+        spec: &NiFiDeploymentSpec,
+    ) -> Result<Option<String>> {
+        let mut data = self.get_config(name, spec);
+        let certificate = json!({ "certificate": {
+            "name": certificate_name,
+            "commonName": hosts.first(),
+            "dnsNames": hosts
+        }});
+        merge_json(&mut data, certificate);
+        self.render(&data, NIFI_CERTIFICATE)
+    }
+
     fn add_ingress(ing: &IngressCfg) -> Value {
         json!({ "ingress": {
                 "enabled": true,
@@ -126,10 +277,33 @@ impl Template {
             } })
     }
 
-    fn get_config(&self, name: &str) -> Value {
+    /// Global NiFi config with the CR overrides merged on top of it
+    fn get_config(&self, name: &str, spec: &NiFiDeploymentSpec) -> Value {
         let mut current_cfg = self.config.clone();
+        if let Some(overrides) = &spec.overrides {
+            merge_json(&mut current_cfg, overrides.clone());
+        }
         let data = json!({ "name": name });
         merge_json(&mut current_cfg, data);
+        merge_json(&mut current_cfg, zk_connection(name, spec));
+        let coordination = json!({ "coordination": {
+            "kubernetes": spec.kubernetes_coordination()
+        }});
+        merge_json(&mut current_cfg, coordination);
+        if spec.tls.is_some() {
+            merge_json(&mut current_cfg, json!({ "protocol": { "isSecure": true }}));
+        }
+        // keystores of both modes are built by NiFi Pods from their <pod>-tls Secret
+        let secret_names = (0..spec.max_nifi_replicas() as i32)
+            .map(|ordinal| node_tls_secret(name, ordinal))
+            .collect::<Vec<_>>();
+        let tls = json!({ "tls": {
+            "stores": spec.tls.is_some(),
+            "secretNames": secret_names,
+            "certManager": spec.tls_mode() == Some(&TlsMode::CertManager),
+            "issuerRef": spec.tls.as_ref().and_then(|t| t.issuer_ref.as_ref()).map(issuer_ref)
+        }});
+        merge_json(&mut current_cfg, tls);
         current_cfg
     }
 
@@ -139,13 +313,18 @@ impl Template {
         ns: &str,
         spec: &NiFiDeploymentSpec,
     ) -> Result<Option<String>> {
-        let mut data = self.get_config(name);
+        let mut data = self.get_config(name, spec);
 
         let replica_indices = (0..spec.nifi_replicas).collect::<Vec<_>>();
         merge_json(
             &mut data,
             json!({ "ns": ns, "nifiReplicas": replica_indices}),
         );
+        let root_node = zk_root_node(name, ns, spec);
+        if root_node.is_empty() {
+            return Err(Error::msg("zk.rootNode has to name a znode below /"));
+        }
+        merge_json(&mut data, json!({ "zk": { "rootNode": root_node }}));
 
         let maybe_ldap = &spec.ldap.clone().map(|al| {
             json!(
@@ -166,7 +345,58 @@ impl Template {
             merge_json(&mut data, json);
         }
 
-        self.configmap(NIFI_CONFIGMAP, &data)
+        let cm = self.configmap(NIFI_CONFIGMAP, &data)?;
+        let mut props = nar_directories(&spec.extensions.clone().unwrap_or_default());
+        props.extend(spec.nifi_properties.clone().unwrap_or_default());
+        if props.is_empty() {
+            Ok(cm)
+        } else {
+            cm.map(|yaml| with_nifi_properties(&yaml, &props))
+                .transpose()
+        }
+    }
+
+    pub fn nifi_secure(&self, name: &str, spec: &NiFiDeploymentSpec) -> bool {
+        self.get_config(name, spec)["protocol"]["isSecure"]
+            .as_bool()
+            .unwrap_or(false)
+    }
+
+    /// NiFi REST API of the first node, which is never removed when the cluster is scaled down
+    pub fn nifi_api_url(&self, name: &str, ns: &str, spec: &NiFiDeploymentSpec) -> String {
+        let config = self.get_config(name, spec);
+        let protocol = &config["protocol"];
+        let (scheme, port) = if self.nifi_secure(name, spec) {
+            ("https", &protocol["httpsPort"])
+        } else {
+            ("http", &protocol["httpPort"])
+        };
+        let port = port
+            .as_u64()
+            .map(|p| p.to_string())
+            .or_else(|| port.as_str().map(String::from))
+            .unwrap_or_default();
+        format!(
+            "{}://{}-0.{}-headless.{}.svc:{}/nifi-api",
+            scheme, name, name, ns, port
+        )
+    }
+
+    /// Job copying the listed volumes of one StatefulSet replica to their migration PVCs
+    pub fn volume_copy_job(
+        &self,
+        name: &str,
+        spec: &NiFiDeploymentSpec,
+        set_name: &str,
+        ordinal: i32,
+        volumes: &[String],
+    ) -> Result<Option<String>> {
+        let mut data = self.get_config(name, spec);
+        merge_json(
+            &mut data,
+            json!({ "setName": set_name, "ordinal": ordinal, "volumes": volumes }),
+        );
+        self.render(&data, VOLUME_COPY_JOB)
     }
 
     fn get_pod_resources(&self, pod_res: &Option<PodResources>, resource_name: &str) -> Value {
@@ -196,8 +426,9 @@ impl Template {
         data
     }
 
-    pub fn zk_configmap(&self, name: &str) -> Result<Option<String>> {
-        let data = self.get_config(name);
+    pub fn zk_configmap(&self, name: &str, spec: &NiFiDeploymentSpec) -> Result<Option<String>> {
+        let mut data = self.get_config(name, spec);
+        merge_json(&mut data, zk_settings(spec));
         self.configmap(ZK_CONFIGMAP, &data)
     }
 
@@ -218,7 +449,7 @@ impl Template {
         name: &str,
         replicas: &u8,
         set_properties: Value,
-        storage_class: &Option<String>,
+        spec: &NiFiDeploymentSpec,
         template: &str,
     ) -> Result<Option<String>> {
         let mut data = json!({
@@ -227,18 +458,302 @@ impl Template {
         });
         merge_json(&mut data, set_properties);
 
-        if let Some(sc) = storage_class {
+        if let Some(sc) = &spec.storage_class {
             let sc_json = json!({ "storageClass": sc });
             merge_json(&mut data, sc_json);
         }
 
-        let mut current_cfg = self.config.clone();
+        let mut current_cfg = self.get_config(name, spec);
         merge_json(&mut current_cfg, data);
+        set_default_storage_class(&mut current_cfg);
         debug!("{} template params:\n{}", &template, &current_cfg);
         self.render(&current_cfg, template)
     }
 }
 
+fn with_nifi_properties(yaml: &str, props: &BTreeMap<String, String>) -> Result<String> {
+    let mut cm: ConfigMap = serde_yaml::from_str(yaml)?;
+    if let Some(content) = cm.data.as_mut().and_then(|d| d.get_mut(NIFI_PROPERTIES)) {
+        *content = override_properties(content, props);
+    }
+    serde_yaml::to_string(&cm).map_err(Error::new)
+}
+
+/// Replaces values of existing keys and appends the rest of the properties to the end
+fn override_properties(content: &str, props: &BTreeMap<String, String>) -> String {
+    let mut missing = props.clone();
+    let mut lines = content
+        .lines()
+        .map(|line| match line.split_once('=') {
+            Some((key, _)) if !line.starts_with('#') => match missing.remove(key.trim()) {
+                Some(value) => format!("{}={}", key, value),
+                None => line.to_string(),
+            },
+            _ => line.to_string(),
+        })
+        .collect::<Vec<_>>();
+    lines.extend(missing.into_iter().map(|(k, v)| format!("{}={}", k, v)));
+    lines.join("\n")
+}
+
+/// Scheduling constraints of a pod template with default anti-affinity between pods of the same instance
+fn scheduling(scheduling: &Option<Scheduling>, name: &str, app: &str) -> Value {
+    let mut data = scheduling
+        .as_ref()
+        .map(spec_json)
+        .unwrap_or_else(|| json!({}));
+    if data.get("affinity").is_none() {
+        let anti_affinity = json!({ "podAntiAffinity": {
+            "preferredDuringSchedulingIgnoredDuringExecution": [{
+                "weight": 100,
+                "podAffinityTerm": {
+                    "labelSelector": { "matchLabels": { "app": app, INSTANCE_LABEL: name }},
+                    "topologyKey": "kubernetes.io/hostname"
+                }
+            }]
+        }});
+        merge_json(&mut data, json!({ "affinity": anti_affinity }));
+    }
+    json!({ "scheduling": data })
+}
+
+/// ZooKeeper tuning. zoo.cfg settings are also rendered as Pod annotation,
+/// so that their change rolls the ZooKeeper Pods.
+fn zk_settings(spec: &NiFiDeploymentSpec) -> Value {
+    let settings = spec.zk.settings.clone().unwrap_or_default();
+    let lines = settings
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .filter(|line| {
+            let single_line = !line.contains(&['\n', '\r'][..]);
+            if !single_line {
+                warn!(
+                    "zoo.cfg setting {:?} is skipped, it spans several lines",
+                    line
+                );
+            }
+            single_line
+        })
+        .map(|line| shell_quote(&line))
+        .collect::<Vec<_>>();
+    let mut zk = json!({
+        "settings": settings,
+        "settingsLines": lines,
+        "settingsAnnotation": serde_json::to_string(&settings).unwrap_or_default()
+    });
+    if let Some(heap_size) = &spec.zk.heap_size {
+        merge_json(&mut zk, json!({ "heapSize": heap_size }));
+    }
+    if let Some(autopurge) = &spec.zk.autopurge {
+        merge_json(&mut zk, json!({ "autopurge": spec_json(autopurge) }));
+    }
+    if let Some(resources) = &spec.zk.resources {
+        merge_json(&mut zk, json!({ "resources": spec_json(resources) }));
+    }
+    json!({ "zk": zk })
+}
+
+/// Single-quoted shell word
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Issuer reference with cert-manager defaults, so that it matches the stored Certificate
+fn issuer_ref(issuer: &IssuerRef) -> Value {
+    json!({
+        "name": issuer.name,
+        "kind": issuer.kind.clone().unwrap_or_else(|| "Issuer".to_string()),
+        "group": issuer.group.clone().unwrap_or_else(|| "cert-manager.io".to_string())
+    })
+}
+
+/// ZooKeeper used by NiFi: deployed by Kubefi or an external one, none for Kubernetes coordination
+fn zk_connection(name: &str, spec: &NiFiDeploymentSpec) -> Value {
+    let connect = match &spec.zk.external {
+        _ if spec.kubernetes_coordination() => String::new(),
+        Some(ext) => ext
+            .connect_string
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+        None => format!("{}-zookeeper:{}", name, ZK_CLIENT_PORT),
+    };
+    let servers = connect
+        .split(',')
+        .filter(|server| !server.trim().is_empty())
+        .map(|server| {
+            let mut parts = server.trim().rsplitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(port), Some(host)) => format!("{} {}", host, port),
+                (Some(host), None) => format!("{} {}", host, ZK_CLIENT_PORT),
+                _ => server.to_string(),
+            }
+        })
+        .collect::<Vec<_>>();
+    json!({ "zk": {
+        "external": spec.zk.external.is_some(),
+        "connect": connect,
+        "servers": servers
+    }})
+}
+
+/// HPA metrics in autoscaling/v2beta2 format. NiFi metrics are exported by the operator,
+/// so they are external metrics selected by the NiFiDeployment namespace and name.
+fn autoscaling_metrics(name: &str, ns: &str, autoscaling: &Autoscaling) -> Vec<Value> {
+    let external = |metric: &str, target: Value| {
+        json!({ "type": "External", "external": {
+            "metric": {
+                "name": metric,
+                "selector": { "matchLabels": { "namespace": ns, "name": name }}
+            },
+            "target": target
+        }})
+    };
+    let mut metrics = Vec::new();
+    if let Some(cpu) = autoscaling.target_cpu_utilization {
+        metrics.push(json!({ "type": "Resource", "resource": {
+            "name": "cpu",
+            "target": { "type": "Utilization", "averageUtilization": cpu }
+        }}));
+    }
+    if let Some(queued) = autoscaling.target_queued_flow_files {
+        let target = json!({ "type": "AverageValue", "averageValue": queued.to_string() });
+        metrics.push(external(FLOW_FILES_QUEUED_METRIC, target));
+    }
+    if let Some(connections) = autoscaling.target_backpressured_connections {
+        let target = json!({ "type": "Value", "value": connections.to_string() });
+        metrics.push(external(BACKPRESSURED_CONNECTIONS_METRIC, target));
+    }
+    metrics.extend(autoscaling.metrics.clone().unwrap_or_default());
+    metrics
+}
+
+/// Every NiFi cluster keeps its state under its own znode, so that clusters can share an ensemble.
+/// The chroot of an external ZooKeeper becomes a prefix of the root node.
+fn zk_root_node(name: &str, ns: &str, spec: &NiFiDeploymentSpec) -> String {
+    let chroot = spec.zk.external.as_ref().and_then(|ext| {
+        ext.chroot.clone().or_else(|| {
+            ext.connect_string
+                .split_once('/')
+                .map(|(_, c)| c.to_string())
+        })
+    });
+    let node = spec
+        .zk
+        .root_node
+        .clone()
+        .unwrap_or_else(|| default_zk_root_node(name, ns));
+    [chroot.unwrap_or_default(), node]
+        .iter()
+        .flat_map(|path| path.split('/'))
+        .filter(|part| !part.is_empty())
+        .fold(String::new(), |root, part| format!("{}/{}", root, part))
+}
+
+/// Secret with the certificate and key of a NiFi node, in kubernetes.io/tls format with ca.crt
+pub fn node_tls_secret(name: &str, ordinal: i32) -> String {
+    format!("{}-{}-tls", name, ordinal)
+}
+
+/// Root node of clusters created without zk.rootNode
+pub fn default_zk_root_node(name: &str, ns: &str) -> String {
+    format!("/nifi/{}/{}", ns, name)
+}
+
+/// Dynamic reconfiguration of the ensemble is supported since ZooKeeper 3.5
+fn dynamic_reconfig(image: &str) -> bool {
+    let name = image.rsplit('/').next().unwrap_or_default();
+    let version = name
+        .split(':')
+        .nth(1)
+        .unwrap_or_default()
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|n| n.parse::<u32>().ok())
+        .take(2)
+        .collect::<Option<Vec<_>>>();
+    match version.as_deref() {
+        Some([major, minor]) => (*major, *minor) >= (3, 5),
+        _ => false,
+    }
+}
+
+/// Extra labels, annotations, environment, volumes and containers of NiFi Pods, including extensions.
+/// Labels used by the StatefulSet selector and anti-affinity cannot be overridden.
+fn pod_settings(spec: &NiFiDeploymentSpec) -> Result<Value> {
+    let mut labels = spec.pod_labels.clone().unwrap_or_default();
+    labels.retain(|k, _| !RESERVED_LABELS.contains(&k.as_str()));
+    let extensions = extension_resources(&spec.extensions.clone().unwrap_or_default())?;
+    let with_extensions = |extra: Value, mut ext: Vec<Value>| {
+        let mut values = match extra {
+            Value::Array(values) => values,
+            _ => Vec::new(),
+        };
+        values.append(&mut ext);
+        values
+    };
+    let annotations = spec.pod_annotations.clone().unwrap_or_default();
+    // keys from the CR, so that removing one of them changes the rendered Pod template
+    let metadata_keys = json!({
+        "labels": labels.keys().collect::<Vec<_>>(),
+        "annotations": annotations.keys().collect::<Vec<_>>()
+    });
+    Ok(json!({
+        "podLabels": labels,
+        "podAnnotations": annotations,
+        "podMetadataKeys": metadata_keys.to_string(),
+        "env": spec_json(&spec.env.clone().unwrap_or_default()),
+        "envFrom": spec_json(&spec.env_from.clone().unwrap_or_default()),
+        "extraVolumes": with_extensions(
+            spec_json(&spec.extra_volumes.clone().unwrap_or_default()),
+            extensions.volumes
+        ),
+        "extraVolumeMounts": with_extensions(
+            spec_json(&spec.extra_volume_mounts.clone().unwrap_or_default()),
+            extensions.volume_mounts
+        ),
+        "initContainers": with_extensions(
+            spec_json(&spec.init_containers.clone().unwrap_or_default()),
+            extensions.init_containers
+        ),
+        "sidecars": spec_json(&spec.sidecars.clone().unwrap_or_default())
+    }))
+}
+
+/// Sets top-level storageClass to every volume which does not define its own
+fn set_default_storage_class(cfg: &mut Value) {
+    let storage_class = cfg["storageClass"].clone();
+    let set_class = |volume: &mut Value| {
+        if let Value::Object(v) = volume {
+            v.entry("storageClass")
+                .or_insert_with(|| storage_class.clone());
+        }
+    };
+    if let Some(Value::Object(volumes)) = cfg.get_mut("storage") {
+        volumes.values_mut().for_each(set_class);
+    }
+    if let Some(volume) = cfg.pointer_mut("/zk/storage") {
+        set_class(volume);
+    }
+}
+
+/// Converts spec value to JSON without unset fields, so that they do not remove config defaults on merge
+fn spec_json<T: Serialize>(value: &T) -> Value {
+    fn without_nulls(v: Value) -> Value {
+        match v {
+            Value::Object(map) => Value::Object(
+                map.into_iter()
+                    .filter(|(_, v)| !v.is_null())
+                    .map(|(k, v)| (k, without_nulls(v)))
+                    .collect(),
+            ),
+            other => other,
+        }
+    }
+    without_nulls(json!(value))
+}
+
 fn merge_json(a: &mut Value, b: Value) {
     if let Value::Object(a) = a {
         if let Value::Object(b) = b {
@@ -1,9 +1,10 @@
 use anyhow::{Error, Result};
 use futures::TryStreamExt;
 use futures_core::stream::BoxStream;
-use kube::api::{Meta, PostParams};
+use kube::api::{ListParams, Meta, PostParams};
 use kube::{Api, Client};
 use kube_runtime::watcher::Event;
+use tokio::time::{interval_at, Duration, Instant};
 
 use crate::controller::{NiFiController, ReplaceStatus};
 use crate::crd::NiFiDeployment;
@@ -13,9 +14,31 @@ pub async fn watch<'a>(
     client: Client,
     watcher: &mut BoxStream<'a, Result<Event<NiFiDeployment>, kube_runtime::watcher::Error>>,
     controller: &NiFiController,
+    resync_interval: Duration,
 ) -> Result<()> {
-    while let Some(event) = watcher.try_next().await? {
-        let status = handle_event(&controller, event.clone()).await?;
+    // the first resync is due one interval after start, watcher lists everything on start itself
+    let mut resync_timer = interval_at(Instant::now() + resync_interval, resync_interval);
+    loop {
+        let event = tokio::select! {
+            next = watcher.try_next() => match next? {
+                Some(event) => event,
+                None => break,
+            },
+            _ = resync_timer.tick() => match resync(client.clone(), &controller.namespace).await {
+                Ok(event) => event,
+                Err(e) => {
+                    error!("Resync failed: {}", e);
+                    continue;
+                }
+            },
+        };
+        let status = match handle_event(&controller, event.clone()).await {
+            Ok(status) => status,
+            Err(e) => {
+                error!("Failed to handle event: {}", e);
+                continue;
+            }
+        };
         for s in status {
             let api = get_api::<NiFiDeployment>(
                 &Namespace::SingleNamespace(s.ns.as_str().to_string()),
@@ -31,6 +54,14 @@ pub async fn watch<'a>(
     )))
 }
 
+/// Re-applies all existing resources, so that long-running operations can report their progress
+async fn resync(client: Client, ns: &Namespace) -> Result<Event<NiFiDeployment>> {
+    debug!("Resyncing all {}", read_type::<NiFiDeployment>("NiFi"));
+    let api = get_api::<NiFiDeployment>(ns, client);
+    let list = api.list(&ListParams::default()).await?;
+    Ok(Event::Restarted(list.items))
+}
+
 async fn replace_status(api: &Api<NiFiDeployment>, s: ReplaceStatus) -> Result<()> {
     debug!("replacing status: {:?}", &s);
     let mut resource = api.get_status(&s.name).await?;